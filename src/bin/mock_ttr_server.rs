@@ -0,0 +1,84 @@
+//! Runs a mock of TTR's servers; see the `mock` module.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+#![deny(deprecated)]
+
+use clap::{App, Arg};
+use shticker_book_unwritten::mock::{MockAccount, MockServer, LOGIN_API_PATH};
+use std::{collections::BTreeMap, fs, net::SocketAddr, process, thread};
+
+fn main() {
+    let arg_matches = App::new("mock_ttr_server")
+        .about("Mock TTR manifest, CDN, and login API, for offline testing")
+        .arg(
+            Arg::with_name("root")
+                .value_name("ROOT_DIR")
+                .help("Directory whose files are served.")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("ADDR")
+                .help("Address to listen on. Defaults to 127.0.0.1:8080.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("accounts")
+                .long("accounts")
+                .value_name("ACCOUNTS_FILE")
+                .help("JSON file of the accounts that the login API accepts.")
+                .long_help(
+                    "JSON file of the accounts that the login API accepts, \
+                     mapping each username to an object with a \
+                     \"password\", and optionally a \"toonguard\" token \
+                     that has to be given as well, and/or a \"queue\" \
+                     length to wait through.",
+                )
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let addr: SocketAddr = match arg_matches
+        .value_of("addr")
+        .unwrap_or("127.0.0.1:8080")
+        .parse()
+    {
+        Ok(addr) => addr,
+        Err(e) => fail("Invalid address", e),
+    };
+    let accounts: BTreeMap<String, MockAccount> =
+        match arg_matches.value_of("accounts") {
+            Some(path) => {
+                match fs::read(path).map_err(|e| e.to_string()).and_then(|j| {
+                    serde_json::from_slice(&j).map_err(|e| e.to_string())
+                }) {
+                    Ok(accounts) => accounts,
+                    Err(e) => fail("Unable to read accounts", e),
+                }
+            }
+            None => BTreeMap::new(),
+        };
+
+    let server = match MockServer::start(
+        addr,
+        arg_matches.value_of("root").unwrap(),
+        accounts,
+    ) {
+        Ok(server) => server,
+        Err(e) => fail("Unable to start the server", e),
+    };
+    println!("Serving at {}", server.uri(""));
+    println!("Login API at {}", server.uri(LOGIN_API_PATH));
+
+    loop {
+        thread::park();
+    }
+}
+
+fn fail<E: std::fmt::Display>(msg: &str, e: E) -> ! {
+    eprintln!("{}: {}", msg, e);
+
+    process::exit(1)
+}
@@ -0,0 +1,78 @@
+use crate::{
+    config::Config,
+    error::{self, Error},
+    retry::RetryPolicy,
+    update::{self, FileFilter, PendingUpdate},
+};
+use reqwest::blocking as rb;
+use serde_json::json;
+
+/// Checks the installation against the manifest without changing anything,
+/// and returns one of the `error::CHECK_*` exit codes. Nothing is printed,
+/// unless `json`, in which case a machine-readable summary is printed to
+/// stdout. Files held back by `filter` never count as needing an update, but
+/// are listed in the summary.
+pub fn check(
+    config: &Config,
+    client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    filter: &FileFilter,
+    keep_going: bool,
+    json: bool,
+) -> Result<i32, Error> {
+    let (pending, held) = match update::update(
+        config,
+        client,
+        true,
+        retry_policy,
+        true,
+        filter,
+        keep_going,
+    ) {
+        Ok(summary) => (summary.pending, summary.held),
+        Err(e) if e.is_manifest_unreachable() => {
+            if json {
+                println!(
+                    "{}",
+                    json!({
+                        "status": "manifest_unreachable",
+                        "error": e.to_string(),
+                    }),
+                );
+            }
+
+            return Ok(error::CHECK_MANIFEST_UNREACHABLE);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (status, code) = if pending
+        .iter()
+        .any(|(_, pu)| *pu == PendingUpdate::Redownload)
+    {
+        ("local_corruption", error::CHECK_LOCAL_CORRUPTION)
+    } else if !pending.is_empty() {
+        ("updates_available", error::CHECK_UPDATES_AVAILABLE)
+    } else {
+        ("up_to_date", error::CHECK_UP_TO_DATE)
+    };
+
+    if json {
+        println!(
+            "{}",
+            json!({
+                "status": status,
+                "pending": pending
+                    .iter()
+                    .map(|(file_name, pu)| json!({
+                        "file": file_name,
+                        "update": pu,
+                    }))
+                    .collect::<Vec<_>>(),
+                "held": held,
+            }),
+        );
+    }
+
+    Ok(code)
+}
@@ -1,9 +1,16 @@
-use crate::{config::Config, error::Error, login, update};
+use crate::{
+    config::Config,
+    error::Error,
+    history, packs, patch,
+    play::{self, Secrets},
+    retry::RetryPolicy,
+    update::{self, FileFilter},
+    vault::Vault,
+};
 use clap::{crate_name, crate_version};
 use reqwest::blocking as rb;
 use std::{
     io::{self, prelude::*},
-    num::NonZeroUsize,
     path::Path,
     process, time,
 };
@@ -15,7 +22,11 @@ Commands
   about                      Display info about this program.
   quit, exit                 Quit this program.
   update, up                 Update the game files. Specify -y or --dry-update
-    [-y | --dry-update]        to only check if updates are available.
+    [-y | --dry-update]        to only check if updates are available. If any
+    [globs...]                 globs are given, only the manifest files that
+                               they match are updated, instead of the ones
+                               included by the config. Excluded files are
+                               always held back.
   login, play, launch        Launch TTR. Specify -n or --no-save to not save
     [usernames...]             logins, even if successful.
     [-n | --no-save]
@@ -24,6 +35,28 @@ Commands
                                instance is specified by its PID or by its
                                username.
   accounts, logins           List all saved accounts/logins.
+  vault migrate              Move any passwords that are saved in plain text
+                               in the config file into the encrypted password
+                               vault.
+  vault lock                 Forget the vault's master password, so that it
+                               has to be entered again.
+  totp set <username>        Save the TOTP secret that <username>'s two-factor
+                               authentication tokens are generated from in the
+                               vault, so that the tokens can be generated
+                               automatically when logging in.
+  totp remove <username>     Remove <username>'s saved TOTP secret.
+  history [count]            Display statistics about the most recent updates
+                               (10 by default).
+  packs [list]               List installed content packs, enabled ones first
+                               (in load order).
+  packs install <path>       Install the content pack at <path>.
+  packs enable <pack>        Enable an installed content pack, or disable an
+  packs disable <pack>         enabled one.
+  packs order <pack> <n>     Move an enabled content pack to position <n> in
+                               the load order.
+  patch inspect <file>       Check that the (decompressed) bsdiff patch at
+                               <file> is valid, without applying it, and
+                               display what it contains.
 ";
 const ABOUT_TEXT: &str = concat!(
     crate_name!(),
@@ -34,6 +67,7 @@ const ABOUT_TEXT: &str = concat!(
     ">\n",
 );
 
+#[allow(clippy::too_many_arguments)]
 pub fn enter_command_mode<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
     config: &mut Config,
     config_path: P,
@@ -41,16 +75,21 @@ pub fn enter_command_mode<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
     quiet: bool,
     maybe_usernames: Option<U>,
     detach: bool,
-    max_tries: NonZeroUsize,
+    retry_policy: &RetryPolicy,
+    filter: &FileFilter,
+    keep_going: bool,
+    secrets: &mut Secrets,
 ) -> Result<(), Error> {
     let mut children = Vec::new();
     if let Some(usernames) = maybe_usernames {
-        login::login(
+        play::login(
             config,
             &config_path,
             client,
+            retry_policy,
             quiet,
             usernames,
+            secrets,
             &mut children,
         )?;
 
@@ -123,7 +162,7 @@ pub fn enter_command_mode<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
                     .map_err(Error::StdinError)?;
 
                 loop {
-                    match command_buf.trim_start().as_bytes().get(0) {
+                    match command_buf.trim_start().as_bytes().first() {
                         Some(b'y') | Some(b'Y') => break 'outer,
                         Some(b'n') | Some(b'N') => break,
                         _ => (),
@@ -141,22 +180,48 @@ pub fn enter_command_mode<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
                 check_children(quiet, &mut children)?;
 
                 let mut dry = false;
+                let mut include = Vec::new();
                 for arg in argv {
                     match arg {
                         "-y" | "--dry-update" => dry = true,
-                        _ => {
+                        _ if arg.starts_with('-') => {
                             println!("Unexpected argument: {}", arg);
 
                             continue 'outer;
                         }
+                        _ => include.push(arg.to_owned()),
                     }
                 }
+                let filter = if include.is_empty() {
+                    filter.clone()
+                } else {
+                    FileFilter {
+                        include,
+                        exclude: filter.exclude.clone(),
+                    }
+                };
 
                 if dry {
-                    update::update(config, client, quiet, max_tries, dry)?
+                    update::update(
+                        config,
+                        client,
+                        quiet,
+                        retry_policy,
+                        dry,
+                        &filter,
+                        keep_going,
+                    )?;
                 } else {
                     if children.is_empty() {
-                        update::update(config, client, quiet, max_tries, dry)?
+                        update::update(
+                            config,
+                            client,
+                            quiet,
+                            retry_policy,
+                            dry,
+                            &filter,
+                            keep_going,
+                        )?;
                     } else if children.len() == 1 {
                         println!(
                             "There's still a game instance running, can't \
@@ -174,12 +239,14 @@ pub fn enter_command_mode<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
                 }
             }
             Some("login") | Some("play") | Some("launch") => {
-                login::login(
+                play::login(
                     config,
                     &config_path,
                     client,
+                    retry_policy,
                     quiet,
                     argv,
+                    secrets,
                     &mut children,
                 )?;
                 check_children(quiet, &mut children)?;
@@ -194,7 +261,58 @@ pub fn enter_command_mode<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
             }
             Some("accounts") | Some("logins") => {
                 check_children(quiet, &mut children)?;
-                display_accounts(config, &children)?;
+                display_accounts(
+                    config,
+                    &config_path,
+                    &secrets.vault,
+                    &children,
+                )?;
+            }
+            Some("totp") => {
+                check_children(quiet, &mut children)?;
+                play::totp_command(
+                    config,
+                    &config_path,
+                    quiet,
+                    secrets,
+                    argv,
+                )?;
+            }
+            Some("vault") => {
+                check_children(quiet, &mut children)?;
+                play::vault_command(
+                    config,
+                    &config_path,
+                    quiet,
+                    secrets,
+                    argv,
+                )?;
+            }
+            Some("history") => {
+                check_children(quiet, &mut children)?;
+
+                let limit = match argv.next().map(str::parse) {
+                    None => history::DEFAULT_HISTORY_LEN,
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => {
+                        println!("Expected a number of updates to display.");
+
+                        continue;
+                    }
+                };
+                history::display(config, limit)?;
+            }
+            Some("packs") | Some("pack") => {
+                check_children(quiet, &mut children)?;
+                packs::command(config, &config_path, quiet, argv)?;
+            }
+            Some("patch") => {
+                check_children(quiet, &mut children)?;
+
+                match (argv.next(), argv.next(), argv.next()) {
+                    (Some("inspect"), Some(path), None) => inspect_patch(path),
+                    _ => println!("Usage: patch inspect <file>"),
+                }
             }
             _ => {
                 check_children(quiet, &mut children)?;
@@ -376,8 +494,36 @@ fn kill_instance(
     Ok(())
 }
 
-fn display_accounts(
+fn inspect_patch(path: &str) {
+    let info = match patch::inspect_file(path) {
+        Ok(info) => info,
+        Err(e) => {
+            println!("Invalid patch!\n{}", e);
+
+            return;
+        }
+    };
+
+    println!("format:             {}", info.header.format);
+    if let Some(bzctrllen) = info.header.bzctrllen {
+        println!("bzctrllen:          {}", bzctrllen);
+    }
+    if let Some(bzdatalen) = info.header.bzdatalen {
+        println!("bzdatalen:          {}", bzdatalen);
+    }
+    println!("new size:           {}", info.header.new_size);
+    println!("control triples:    {}", info.ctrl_triples);
+    println!("bytes from old:     {}", info.old_bytes);
+    println!("bytes from diff:    {}", info.diff_bytes);
+    println!("bytes from extra:   {}", info.extra_bytes);
+    println!("minimum old size:   {}", info.min_old_size);
+    println!("The patch is valid.");
+}
+
+fn display_accounts<P: AsRef<Path>>(
     config: &Config,
+    config_path: P,
+    vault: &Option<Vault>,
     children: &[(String, process::Child, time::Instant)],
 ) -> Result<(), Error> {
     let max_name_len = if let Some(l) =
@@ -388,9 +534,23 @@ fn display_accounts(
         return Ok(());
     };
 
-    for (username, saved_password) in
-        config.accounts.iter().map(|(un, p)| (un, p.is_string()))
-    {
+    let vault_exists = config.vault_path(config_path).exists();
+    for (username, saved_password) in config.accounts.iter().map(|(un, p)| {
+        (
+            un,
+            if p.is_string() {
+                "yes (in plain text)"
+            } else if config.credential_helper.is_some() {
+                "up to the credential helper"
+            } else {
+                match vault {
+                    Some(v) if v.contains(un) => "yes",
+                    None if vault_exists => "locked",
+                    _ => "no",
+                }
+            },
+        )
+    }) {
         print!(
             "{} {}   ",
             if children.iter().any(|(un, _, _)| un == username) {
@@ -403,7 +563,7 @@ fn display_accounts(
         for _ in 0..max_name_len - username.len() {
             print!(" ");
         }
-        println!("Password?: {}", if saved_password { "yes" } else { "no" });
+        println!("Password?: {}", saved_password);
     }
 
     Ok(())
@@ -411,7 +571,7 @@ fn display_accounts(
 
 /// Naïve implementation because, let's be real, how many instances of the game
 /// are you really going to run concurrently?
-fn check_children(
+pub fn check_children(
     quiet: bool,
     children: &mut Vec<(String, process::Child, time::Instant)>,
 ) -> Result<(), Error> {
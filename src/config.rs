@@ -1,11 +1,9 @@
-use crate::{error::Error, util};
+use crate::{error::Error, util, vault};
 use clap::crate_name;
-use serde::{Deserialize, Serialize};
-use serde_json;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{
-    env,
-    fs::{self, File},
-    io::{self, Write},
+    collections::BTreeSet,
+    env, io,
     path::{Path, PathBuf},
 };
 
@@ -13,270 +11,311 @@ const DEFAULT_MANIFEST_URI: &str =
     "https://cdn.toontownrewritten.com/content/patchmanifest.txt";
 const DEFAULT_CDN_URI: &str =
     "https://download.toontownrewritten.com/patches/";
+const DEFAULT_LOGIN_API_URI: &str =
+    "https://www.toontownrewritten.com/api/login?format=json";
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     pub install_dir: PathBuf,
     pub cache_dir: PathBuf,
-    pub manifest_uri: String,
-    pub cdn_uri: String,
+    /// Mirrors of the manifest, in order of preference. Besides HTTP(S)
+    /// URIs, these may be `file://` URIs or plain paths.
+    #[serde(alias = "manifest_uri", deserialize_with = "one_or_many")]
+    pub manifest_uris: Vec<String>,
+    /// Mirrors of the CDN, in order of preference. Besides HTTP(S) URIs,
+    /// these may be `file://` URIs or plain paths of local directories.
+    #[serde(alias = "cdn_uri", deserialize_with = "one_or_many")]
+    pub cdn_uris: Vec<String>,
+    #[serde(default = "default_login_api_uri")]
+    pub login_api_uri: String,
+    /// Whether passwords are saved, in the encrypted vault, after logging
+    /// in successfully.
     pub store_passwords: bool,
+    /// Where the encrypted password vault is kept. Defaults to `vault.json`
+    /// in the same directory as the config file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault: Option<PathBuf>,
+    /// A command that passwords are got from and handed to, instead of the
+    /// vault. See the `credential` module for how it is run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential_helper: Option<String>,
+    /// Saved accounts. Their values are `null`, except for passwords saved
+    /// in plain text by older versions, which are strings until they are
+    /// migrated into the vault.
     pub accounts: serde_json::Map<String, serde_json::Value>,
+    /// Accounts that have a TOTP secret saved in the vault, so that the vault
+    /// is only unlocked for two-factor logins that it can actually help with.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub totp_accounts: BTreeSet<String>,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    /// Globs over manifest file names. If any are given, only matching files
+    /// are updated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Globs over manifest file names. Matching files are held back, i.e.
+    /// never updated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub packs: PacksConfig,
+    /// Whether updates keep going after a file fails to update. Defaults to
+    /// on for background updates, and off otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_going: Option<bool>,
+}
+
+/// Settings for the HTTP client that is used for the manifest, the CDN, and
+/// the login API. Everything is optional, and defaults to whatever `reqwest`
+/// does by default.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct NetworkConfig {
+    /// URI of a proxy to send all requests through: `http://`, `https://`,
+    /// `socks5://`, or `socks5h://` (SOCKS5 with DNS resolved by the proxy).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// PEM files containing extra root CA certificates to trust.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ca_certs: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_family: Option<IpFamily>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// Shell commands to run around updates. See the `hooks` module for what
+/// they are passed.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct HooksConfig {
+    /// Run before any files are updated. If this fails, the update is
+    /// aborted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_update: Option<String>,
+    /// Run after the update has finished, whether or not it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_update: Option<String>,
+}
+
+/// Content packs, as managed by the `packs` command.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PacksConfig {
+    /// Where installed packs are kept. Defaults to `packs` in the cache
+    /// directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// Names of the enabled packs, in the order that they are given to the
+    /// game.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub enabled: Vec<String>,
 }
 
 impl Config {
-    /// Same return type as `BTreeMap::insert`.
+    /// A config with the default mirrors and no accounts, which doesn't
+    /// store passwords.
+    pub fn new<P: Into<PathBuf>, Q: Into<PathBuf>>(
+        install_dir: P,
+        cache_dir: Q,
+    ) -> Self {
+        Self {
+            install_dir: install_dir.into(),
+            cache_dir: cache_dir.into(),
+            manifest_uris: vec![DEFAULT_MANIFEST_URI.to_owned()],
+            cdn_uris: vec![DEFAULT_CDN_URI.to_owned()],
+            login_api_uri: DEFAULT_LOGIN_API_URI.to_owned(),
+            store_passwords: false,
+            vault: None,
+            credential_helper: None,
+            accounts: serde_json::Map::default(),
+            totp_accounts: BTreeSet::new(),
+            network: NetworkConfig::default(),
+            hooks: HooksConfig::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            packs: PacksConfig::default(),
+            keep_going: None,
+        }
+    }
+
+    /// Saves `username` as an account. Passwords are never saved here; they
+    /// go in the vault instead. Same return type as `BTreeMap::insert`.
     pub fn add_account(
         &mut self,
         username: String,
-        password: String,
     ) -> Option<serde_json::Value> {
-        if self.store_passwords {
-            self.accounts
-                .insert(username, serde_json::Value::String(password))
-        } else {
-            self.accounts.insert(username, serde_json::Value::Null)
-        }
+        self.accounts.insert(username, serde_json::Value::Null)
     }
-}
 
-pub fn get_config(
-    no_config: bool,
-    config_path: Option<&str>,
-    install_path: Option<&str>,
-    cache_path: Option<&str>,
-    quiet: bool,
-) -> Result<(Config, PathBuf), Error> {
-    let inject_arg_values = |c| {
-        let c = if let Some(ip) = install_path {
-            Config {
-                install_dir: PathBuf::from(ip),
-                ..c
-            }
-        } else {
-            c
-        };
+    /// The password saved in plain text for `username`, if any.
+    pub fn plaintext_password(&self, username: &str) -> Option<&str> {
+        self.accounts
+            .get(username)
+            .and_then(serde_json::Value::as_str)
+    }
 
-        if let Some(cp) = cache_path {
-            Config {
-                cache_dir: PathBuf::from(cp),
-                ..c
-            }
+    /// Whether any passwords are still saved in plain text, and so should be
+    /// migrated into the vault.
+    pub fn has_plaintext_passwords(&self) -> bool {
+        self.accounts.values().any(serde_json::Value::is_string)
+    }
+
+    /// Makes sure that there is at least one manifest mirror, and at least
+    /// one CDN mirror.
+    pub fn check_mirrors(&self) -> Result<(), Error> {
+        if self.manifest_uris.is_empty() {
+            Err(Error::NoMirrors("manifest_uris"))
+        } else if self.cdn_uris.is_empty() {
+            Err(Error::NoMirrors("cdn_uris"))
         } else {
-            c
+            Ok(())
         }
-    };
+    }
 
-    if !no_config {
-        let config_path = if let Some(s) = config_path {
-            PathBuf::from(s)
-        } else {
-            #[cfg(target_os = "linux")]
-            {
-                let mut xdg_config_home = String::new();
-                let mut home = String::new();
+    /// Where the password vault is, given that the config file is at
+    /// `config_path`.
+    pub fn vault_path<P: AsRef<Path>>(&self, config_path: P) -> PathBuf {
+        self.vault.clone().unwrap_or_else(|| {
+            config_path
+                .as_ref()
+                .with_file_name(vault::DEFAULT_VAULT_FILE_NAME)
+        })
+    }
+}
 
-                for (key, value) in env::vars() {
-                    match key.as_str() {
-                        "XDG_CONFIG_HOME" => xdg_config_home = value,
-                        "HOME" => home = value,
-                        _ => {
-                            if !(home.is_empty() || xdg_config_home.is_empty())
-                            {
-                                break;
-                            }
-                        }
-                    }
-                }
+/// Reads the config file at `config_path`, without prompting for anything.
+pub fn load_config<P: AsRef<Path>>(config_path: P) -> Result<Config, Error> {
+    read_config(util::open_file(config_path)?)
+}
 
-                if !xdg_config_home.is_empty() {
-                    [xdg_config_home.as_str(), crate_name!(), "config.json"]
-                        .iter()
-                        .collect()
-                } else if !home.is_empty() {
-                    [home.as_str(), ".config", crate_name!(), "config.json"]
-                        .iter()
-                        .collect()
-                } else {
-                    return Err(Error::NoPossibleConfigPath);
-                }
-            }
-            #[cfg(windows)]
-            {
-                let mut appdata = String::new();
+/// Where the config file is kept by default, which depends on the OS and on
+/// the environment.
+pub fn default_config_path() -> Result<PathBuf, Error> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut xdg_config_home = String::new();
+        let mut home = String::new();
 
-                for (key, value) in env::vars() {
-                    match key.as_str() {
-                        "APPDATA" => appdata = value,
-                        _ => {
-                            if !appdata.is_empty() {
-                                break;
-                            }
-                        }
+        for (key, value) in env::vars() {
+            match key.as_str() {
+                "XDG_CONFIG_HOME" => xdg_config_home = value,
+                "HOME" => home = value,
+                _ => {
+                    if !(home.is_empty() || xdg_config_home.is_empty()) {
+                        break;
                     }
                 }
-
-                if !appdata.is_empty() {
-                    [appdata.as_str(), crate_name!(), "config.json"]
-                        .iter()
-                        .collect()
-                } else {
-                    return Err(Error::NoPossibleConfigPath);
-                }
             }
-            #[cfg(target_os = "macos")]
-            {
-                let mut home = String::new();
+        }
 
-                for (key, value) in env::vars() {
-                    match key.as_str() {
-                        "HOME" => home = value,
-                        _ => {
-                            if !(home.is_empty()) {
-                                break;
-                            }
-                        }
-                    }
-                }
+        if !xdg_config_home.is_empty() {
+            Ok([xdg_config_home.as_str(), crate_name!(), "config.json"]
+                .iter()
+                .collect())
+        } else if !home.is_empty() {
+            Ok([home.as_str(), ".config", crate_name!(), "config.json"]
+                .iter()
+                .collect())
+        } else {
+            Err(Error::NoPossibleConfigPath)
+        }
+    }
+    #[cfg(windows)]
+    {
+        let mut appdata = String::new();
 
-                if !home.is_empty() {
-                    [
-                        home.as_str(),
-                        "Library",
-                        "Preferences",
-                        crate_name!(),
-                        "config.json",
-                    ]
-                    .iter()
-                    .collect()
-                } else {
-                    return Err(Error::NoPossibleConfigPath);
+        for (key, value) in env::vars() {
+            match key.as_str() {
+                "APPDATA" => appdata = value,
+                _ => {
+                    if !appdata.is_empty() {
+                        break;
+                    }
                 }
             }
-        };
-
-        if !quiet {
-            println!("Using {} as the config path...", config_path.display());
         }
 
-        match File::open(&config_path) {
-            Ok(f) => serde_json::from_reader(f)
-                .map_err(Error::DeserializeError)
-                .map(|c| (inject_arg_values(c), config_path)),
-            Err(ioe) => match ioe.kind() {
-                io::ErrorKind::NotFound => {
-                    let config_dir =
-                        config_path.parent().ok_or_else(|| {
-                            Error::BadConfigPath(config_path.clone())
-                        })?;
-                    fs::create_dir_all(config_dir).map_err(|ioe| {
-                        Error::MkdirError(config_dir.to_path_buf(), ioe)
-                    })?;
-
-                    let mut new_config_file = util::create_file(&config_path)?;
-                    let new_config = prompt_for_config_values(&config_path)?;
-
-                    serde_json::to_writer_pretty(
-                        &mut new_config_file,
-                        &new_config,
-                    )
-                    .map_err(Error::SerializeError)?;
+        if !appdata.is_empty() {
+            Ok([appdata.as_str(), crate_name!(), "config.json"]
+                .iter()
+                .collect())
+        } else {
+            Err(Error::NoPossibleConfigPath)
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let mut home = String::new();
 
-                    Ok((inject_arg_values(new_config), config_path))
-                }
-                io::ErrorKind::PermissionDenied => {
-                    Err(Error::PermissionDenied(
-                        format!("opening {:?}", config_path),
-                        ioe,
-                    ))
+        for (key, value) in env::vars() {
+            match key.as_str() {
+                "HOME" => home = value,
+                _ => {
+                    if !(home.is_empty()) {
+                        break;
+                    }
                 }
-                _ => Err(Error::UnknownIoError(
-                    format!("opening {:?}", config_path),
-                    ioe,
-                )),
-            },
-        }
-    } else {
-        if !quiet {
-            println!("Not using any config file...");
+            }
         }
 
-        Ok((
-            Config {
-                install_dir: PathBuf::from(install_path.ok_or_else(|| {
-                    Error::MissingCommandLineArg("--install-dir")
-                })?),
-                cache_dir: PathBuf::from(cache_path.ok_or_else(|| {
-                    Error::MissingCommandLineArg("--cache-dir")
-                })?),
-                manifest_uri: DEFAULT_MANIFEST_URI.to_owned(),
-                cdn_uri: DEFAULT_CDN_URI.to_owned(),
-                store_passwords: false,
-                accounts: serde_json::Map::default(),
-            },
-            PathBuf::new(),
-        ))
+        if !home.is_empty() {
+            Ok([
+                home.as_str(),
+                "Library",
+                "Preferences",
+                crate_name!(),
+                "config.json",
+            ]
+            .iter()
+            .collect())
+        } else {
+            Err(Error::NoPossibleConfigPath)
+        }
     }
 }
 
-fn prompt_for_config_values<P: AsRef<Path>>(
-    config_path: P,
-) -> Result<Config, Error> {
-    print!(
-        "No configuration file was found at {}\nAnswer a few prompts, and a \
-         new config file will be created there.\n\nFully qualified path to \
-         desired TTR installation directory\n(will be created if it doesn't \
-         already exist):\n> ",
-        config_path.as_ref().display(),
-    );
-    io::stdout().flush().map_err(Error::StdoutError)?;
-    let mut install_dir = String::with_capacity(0x30);
-    io::stdin()
-        .read_line(&mut install_dir)
-        .map_err(Error::StdinError)?;
+fn default_login_api_uri() -> String {
+    DEFAULT_LOGIN_API_URI.to_owned()
+}
 
-    print!(
-        "\nDo you want passwords for your accounts to be stored in the \
-         config file? [yes/no]\nThe passwords will be stored IN PLAIN TEXT, \
-         so if you want your passwords to be managed without storing them on \
-         your hard drive in plain text, you will have to use a separate \
-         password manager app:\n> "
-    );
-    io::stdout().flush().map_err(Error::StdoutError)?;
-    let mut yes_no = String::with_capacity(4);
-    io::stdin()
-        .read_line(&mut yes_no)
-        .map_err(Error::StdinError)?;
-    yes_no.make_ascii_lowercase();
-    loop {
-        let yes_no_trimmed = yes_no.as_str().trim();
-        if yes_no_trimmed == "yes" || yes_no_trimmed == "no" {
-            println!();
+/// Older configs have a single `manifest_uri` and a single `cdn_uri`, which
+/// are read as lists of one mirror each.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
 
-            return Ok(Config {
-                install_dir: PathBuf::from(install_dir.trim()),
-                cache_dir: config_path
-                    .as_ref()
-                    .parent()
-                    .ok_or_else(|| {
-                        Error::BadConfigPath(config_path.as_ref().to_owned())
-                    })?
-                    .join("cache"),
-                manifest_uri: DEFAULT_MANIFEST_URI.to_owned(),
-                cdn_uri: DEFAULT_CDN_URI.to_owned(),
-                store_passwords: yes_no_trimmed == "yes",
-                accounts: serde_json::Map::default(),
-            });
-        }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
 
-        print!("Please enter \"yes\" or \"no\" (without quotes):\n> ");
-        io::stdout().flush().map_err(Error::StdoutError)?;
-        yes_no.clear();
-        io::stdin()
-            .read_line(&mut yes_no)
-            .map_err(Error::StdinError)?;
-        yes_no.make_ascii_lowercase();
-    }
+/// Reads a config from `r`, making sure that it is usable.
+pub fn read_config<R: io::Read>(r: R) -> Result<Config, Error> {
+    serde_json::from_reader(r)
+        .map_err(Error::DeserializeError)
+        .and_then(validate)
+}
+
+fn validate(config: Config) -> Result<Config, Error> {
+    config.check_mirrors().map(|_| config)
 }
 
 pub fn commit_config<P: AsRef<Path>>(
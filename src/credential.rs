@@ -0,0 +1,132 @@
+//! External credential helpers, in the style of git's: a command named by
+//! the config that passwords are got from and handed to, so that they can be
+//! kept by a password manager (`pass`, Bitwarden CLI, 1Password CLI, ...)
+//! without ever being saved by us.
+//!
+//! The helper is run by the platform's shell, with an action and a username
+//! appended to it as arguments:
+//!
+//! - `get <username>`: print the password on stdout. Printing nothing, or
+//!   exiting unsuccessfully, means that there is no password for
+//!   `<username>`.
+//! - `store <username>`: the password, which was just used to log in
+//!   successfully, is written to the helper's stdin.
+//! - `erase <username>`: the login API rejected the password for
+//!   `<username>`.
+//! - `totp <username>`: like `get`, but for the base32-encoded TOTP secret
+//!   that two-factor authentication tokens for `<username>` are generated
+//!   from, if it has one. Helpers that don't know about this can just print
+//!   nothing.
+//!
+//! stderr is left alone either way, so the helper can prompt for whatever it
+//! needs to in order to unlock itself.
+
+use crate::error::Error;
+use std::{
+    io::Write,
+    process::{self, Stdio},
+};
+
+/// Asks `helper` for the password for `username`.
+pub fn get(helper: &str, username: &str) -> Result<Option<String>, Error> {
+    get_line(helper, "get", username)
+}
+
+/// Asks `helper` for the (base32-encoded) TOTP secret for `username`.
+pub fn get_totp_secret(
+    helper: &str,
+    username: &str,
+) -> Result<Option<String>, Error> {
+    get_line(helper, "totp", username)
+}
+
+/// Hands `password` (which worked) for `username` to `helper`.
+pub fn store(
+    helper: &str,
+    username: &str,
+    password: &str,
+) -> Result<(), Error> {
+    let mut child = command(helper, "store", username)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|ioe| Error::CredentialHelperError(helper.to_owned(), ioe))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", password).map_err(|ioe| {
+            Error::CredentialHelperError(helper.to_owned(), ioe)
+        })?;
+    }
+
+    let status = child
+        .wait()
+        .map_err(|ioe| Error::CredentialHelperError(helper.to_owned(), ioe))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::CredentialHelperFailed(
+            helper.to_owned(),
+            status.code(),
+        ))
+    }
+}
+
+/// Tells `helper` that its password for `username` was rejected.
+pub fn erase(helper: &str, username: &str) -> Result<(), Error> {
+    let status = command(helper, "erase", username)
+        .status()
+        .map_err(|ioe| Error::CredentialHelperError(helper.to_owned(), ioe))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::CredentialHelperFailed(
+            helper.to_owned(),
+            status.code(),
+        ))
+    }
+}
+
+fn get_line(
+    helper: &str,
+    action: &str,
+    username: &str,
+) -> Result<Option<String>, Error> {
+    let output = command(helper, action, username)
+        .stdout(Stdio::piped())
+        .spawn()
+        .and_then(process::Child::wait_with_output)
+        .map_err(|ioe| Error::CredentialHelperError(helper.to_owned(), ioe))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout.lines().next().unwrap_or("");
+
+    Ok(if line.is_empty() {
+        None
+    } else {
+        Some(line.to_owned())
+    })
+}
+
+fn command(helper: &str, action: &str, username: &str) -> process::Command {
+    #[cfg(windows)]
+    {
+        let mut cmd = process::Command::new("cmd");
+        cmd.arg("/C").arg(helper).arg(action).arg(username);
+
+        cmd
+    }
+    #[cfg(not(windows))]
+    {
+        // Passing the arguments as positional parameters, rather than pasting
+        // them into the command, keeps the shell from interpreting them.
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!("{} \"$@\"", helper))
+            .arg("sh")
+            .arg(action)
+            .arg(username);
+
+        cmd
+    }
+}
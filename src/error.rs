@@ -1,7 +1,16 @@
-use reqwest;
-use serde_json;
-use std::{error, fmt, io, path::PathBuf};
+use std::{error, fmt, io, path::PathBuf, time::Duration};
 
+/// Exit code of `--check` when everything is up to date.
+pub const CHECK_UP_TO_DATE: i32 = 0;
+/// Exit code of `--check` when there are updates to download or apply.
+pub const CHECK_UPDATES_AVAILABLE: i32 = 100;
+/// Exit code of `--check` when the manifest couldn't be downloaded.
+pub const CHECK_MANIFEST_UNREACHABLE: i32 = 101;
+/// Exit code of `--check` when a local file doesn't match any version known
+/// to the manifest.
+pub const CHECK_LOCAL_CORRUPTION: i32 = 102;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     NoPossibleConfigPath,
@@ -14,12 +23,12 @@ pub enum Error {
     SerializeError(serde_json::Error),
     DeserializeError(serde_json::Error),
     ManifestRequestError(reqwest::Error),
-    ManifestRequestStatusError(reqwest::StatusCode),
+    ManifestRequestStatusError(reqwest::StatusCode, Option<Duration>),
     BadManifestFormat(String),
     FileReadError(PathBuf, io::Error),
     FileWriteError(PathBuf, io::Error),
     DownloadRequestError(reqwest::Error),
-    DownloadRequestStatusError(reqwest::StatusCode),
+    DownloadRequestStatusError(reqwest::StatusCode, Option<Duration>),
     CopyIntoFileError(PathBuf, reqwest::Error),
     DecodeError(PathBuf, io::Error),
     BadPatchVersion,
@@ -44,6 +53,31 @@ pub enum Error {
     ProcessKillError(u32, io::Error),
     HashMismatch(PathBuf, [u8; 20]),
     InvalidArgValue(&'static str),
+    PostStatusError(reqwest::StatusCode, Option<Duration>),
+    NoMirrors(&'static str),
+    BadProxy(String, reqwest::Error),
+    BadCertificate(PathBuf, reqwest::Error),
+    HookSpawnError(String, io::Error),
+    PreUpdateHookFailed(String, Option<i32>),
+    BadMultifile(PathBuf, &'static str),
+    UpdateIncomplete(usize),
+    PatchInconsistent(&'static str),
+    PatchReadError(io::Error),
+    PatchDecodeError(io::Error),
+    OldDataReadError(io::Error),
+    NewDataWriteError(io::Error),
+    ManifestReadError(PathBuf, io::Error),
+    BadVault(PathBuf, &'static str),
+    VaultDecryptError(PathBuf),
+    VaultEncryptError(PathBuf),
+    RandomnessError(getrandom::Error),
+    CredentialHelperError(String, io::Error),
+    CredentialHelperFailed(String, Option<i32>),
+    MissingEnvVar(String),
+    SecretsExhausted(&'static str),
+    BadTotpSecret,
+    EmptyMasterPassword,
+    BadFileUri(String, &'static str),
 }
 
 impl fmt::Display for Error {
@@ -87,7 +121,7 @@ impl fmt::Display for Error {
             Self::ManifestRequestError(mre) => {
                 write!(f, "Error requesting manifest:\n\t{}", mre)
             }
-            Self::ManifestRequestStatusError(sc) => write!(
+            Self::ManifestRequestStatusError(sc, _) => write!(
                 f,
                 "Bad status code after requesting manifest:\n\t{}",
                 sc,
@@ -104,7 +138,7 @@ impl fmt::Display for Error {
             Self::DownloadRequestError(dre) => {
                 write!(f, "Error requesting download: {}", dre)
             }
-            Self::DownloadRequestStatusError(sc) => {
+            Self::DownloadRequestStatusError(sc, _) => {
                 write!(f, "Bad status code after requesting download: {}", sc)
             }
             Self::CopyIntoFileError(path, cife) => write!(
@@ -193,6 +227,105 @@ impl fmt::Display for Error {
             Self::InvalidArgValue(param) => {
                 write!(f, "Invalid value for the argument of {}", param)
             }
+            Self::PostStatusError(sc, _) => {
+                write!(f, "Bad status code after sending HTTP POST: {}", sc)
+            }
+            Self::NoMirrors(key) => {
+                write!(f, "Expected at least one URI in the config's {}", key)
+            }
+            Self::BadProxy(uri, re) => {
+                write!(f, "Unable to use {:?} as a proxy:\n\t{}", uri, re)
+            }
+            Self::BadCertificate(path, re) => write!(
+                f,
+                "Unable to read a certificate from {:?}:\n\t{}",
+                path, re,
+            ),
+            Self::HookSpawnError(command, ioe) => {
+                write!(f, "Error running hook {:?}:\n\t{}", command, ioe)
+            }
+            Self::PreUpdateHookFailed(command, Some(code)) => write!(
+                f,
+                "Pre-update hook {:?} exited with status {}, aborting update",
+                command, code,
+            ),
+            Self::PreUpdateHookFailed(command, None) => write!(
+                f,
+                "Pre-update hook {:?} was killed by a signal, aborting update",
+                command,
+            ),
+            Self::BadMultifile(path, msg) => {
+                write!(f, "{:?} is not a valid multifile: {}", path, msg)
+            }
+            Self::UpdateIncomplete(n) => {
+                write!(f, "{} file(s) failed to update", n)
+            }
+            Self::PatchInconsistent(msg) => {
+                write!(f, "Patch is inconsistent: {}", msg)
+            }
+            Self::PatchReadError(ioe) => {
+                write!(f, "Failed to read patch:\n\t{}", ioe)
+            }
+            Self::PatchDecodeError(ioe) => {
+                write!(f, "Error decoding bzip2 in patch:\n\t{}", ioe)
+            }
+            Self::OldDataReadError(ioe) => {
+                write!(f, "Failed to read the data being patched:\n\t{}", ioe)
+            }
+            Self::NewDataWriteError(ioe) => {
+                write!(f, "Failed to write the patched data:\n\t{}", ioe)
+            }
+            Self::ManifestReadError(path, ioe) => {
+                write!(f, "Error reading manifest from {:?}:\n\t{}", path, ioe)
+            }
+            Self::BadVault(path, msg) => {
+                write!(f, "{:?} is not a valid password vault: {}", path, msg)
+            }
+            Self::VaultDecryptError(path) => write!(
+                f,
+                "Failed to decrypt the password vault at {:?}. Either the \
+                 master password is wrong, or the vault is corrupted.",
+                path,
+            ),
+            Self::VaultEncryptError(path) => {
+                write!(f, "Failed to encrypt the password vault at {:?}", path)
+            }
+            Self::RandomnessError(re) => {
+                write!(f, "Failed to get random bytes from the OS:\n\t{}", re)
+            }
+            Self::CredentialHelperError(helper, ioe) => write!(
+                f,
+                "Error running credential helper {:?}:\n\t{}",
+                helper, ioe,
+            ),
+            Self::CredentialHelperFailed(helper, Some(code)) => write!(
+                f,
+                "Credential helper {:?} exited with status {}",
+                helper, code,
+            ),
+            Self::CredentialHelperFailed(helper, None) => write!(
+                f,
+                "Credential helper {:?} was killed by a signal",
+                helper,
+            ),
+            Self::MissingEnvVar(var) => write!(
+                f,
+                "The environment variable {} isn't set, or isn't valid \
+                 unicode",
+                var,
+            ),
+            Self::SecretsExhausted(what) => {
+                write!(f, "Ran out of input to read a {} from", what)
+            }
+            Self::BadTotpSecret => {
+                f.write_str("The TOTP secret isn't valid base32")
+            }
+            Self::EmptyMasterPassword => {
+                f.write_str("The vault's master password can't be empty")
+            }
+            Self::BadFileUri(uri, msg) => {
+                write!(f, "Unable to use {:?} as a file URI: {}", uri, msg)
+            }
         }
     }
 }
@@ -212,12 +345,12 @@ impl Error {
             Self::SerializeError(_) => 8,
             Self::DeserializeError(_) => 9,
             Self::ManifestRequestError(_) => 10,
-            Self::ManifestRequestStatusError(_) => 11,
+            Self::ManifestRequestStatusError(_, _) => 11,
             Self::BadManifestFormat(_) => 12,
             Self::FileReadError(_, _) => 13,
             Self::FileWriteError(_, _) => 14,
             Self::DownloadRequestError(_) => 15,
-            Self::DownloadRequestStatusError(_) => 16,
+            Self::DownloadRequestStatusError(_, _) => 16,
             Self::CopyIntoFileError(_, _) => 17,
             Self::DecodeError(_, _) => 18,
             Self::BadPatchVersion => 19,
@@ -240,6 +373,79 @@ impl Error {
             Self::ProcessKillError(_, _) => 36,
             Self::HashMismatch(_, _) => 37,
             Self::InvalidArgValue(_) => 38,
+            Self::PostStatusError(_, _) => 39,
+            Self::NoMirrors(_) => 40,
+            Self::BadProxy(_, _) => 41,
+            Self::BadCertificate(_, _) => 42,
+            Self::HookSpawnError(_, _) => 43,
+            Self::PreUpdateHookFailed(_, _) => 44,
+            Self::BadMultifile(_, _) => 45,
+            Self::UpdateIncomplete(_) => 46,
+            Self::PatchInconsistent(_) => 47,
+            Self::PatchReadError(_) => 48,
+            Self::PatchDecodeError(_) => 49,
+            Self::OldDataReadError(_) => 50,
+            Self::NewDataWriteError(_) => 51,
+            Self::ManifestReadError(_, _) => 52,
+            Self::BadVault(_, _) => 53,
+            Self::VaultDecryptError(_) => 54,
+            Self::VaultEncryptError(_) => 55,
+            Self::RandomnessError(_) => 56,
+            Self::CredentialHelperError(_, _) => 57,
+            Self::CredentialHelperFailed(_, _) => 58,
+            Self::MissingEnvVar(_) => 59,
+            Self::SecretsExhausted(_) => 60,
+            Self::BadTotpSecret => 61,
+            Self::EmptyMasterPassword => 62,
+            Self::BadFileUri(_, _) => 63,
+        }
+    }
+
+    /// Whether or not trying the same thing again might make this error go
+    /// away. Timeouts, dropped connections, server-side (5xx) errors, and
+    /// corrupted downloads are transient; things like 404s and malformed
+    /// JSON are not.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::ManifestRequestError(re)
+            | Self::DownloadRequestError(re)
+            | Self::CopyIntoFileError(_, re)
+            | Self::PostError(re) => {
+                re.is_timeout()
+                    || re.is_connect()
+                    || re.is_request()
+                    || re.is_body()
+            }
+            Self::ManifestRequestStatusError(sc, _)
+            | Self::DownloadRequestStatusError(sc, _)
+            | Self::PostStatusError(sc, _) => {
+                sc.is_server_error()
+                    || *sc == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || *sc == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::HashMismatch(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether or not this error means that the manifest couldn't be
+    /// obtained from the server at all.
+    pub fn is_manifest_unreachable(&self) -> bool {
+        matches!(
+            self,
+            Self::ManifestRequestError(_)
+                | Self::ManifestRequestStatusError(_, _)
+                | Self::ManifestReadError(_, _),
+        )
+    }
+
+    /// How long the server asked us to wait before trying again, if at all.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::ManifestRequestStatusError(_, ra)
+            | Self::DownloadRequestStatusError(_, ra)
+            | Self::PostStatusError(_, ra) => *ra,
+            _ => None,
         }
     }
 }
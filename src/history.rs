@@ -0,0 +1,225 @@
+use crate::{
+    config::Config,
+    error::Error,
+    update::{UpdateStats, UpdateSummary},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{self, prelude::*, BufReader},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// One line per update, in JSON, oldest first.
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+/// How many updates the `history` command shows by default.
+pub const DEFAULT_HISTORY_LEN: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch at which the update finished.
+    pub timestamp: u64,
+    pub succeeded: bool,
+    pub manifest_uri: String,
+    pub patched: Vec<String>,
+    pub downloaded: Vec<String>,
+    pub failed: Vec<String>,
+    /// The name of each file downloaded from the CDN, alongside the mirror
+    /// that it came from.
+    pub downloads: Vec<(String, String)>,
+    pub stats: UpdateStats,
+}
+
+fn history_path(config: &Config) -> PathBuf {
+    config.cache_dir.join(HISTORY_FILE_NAME)
+}
+
+/// Appends the results of an update to the history file in the cache
+/// directory.
+pub fn record(
+    config: &Config,
+    summary: &UpdateSummary,
+    succeeded: bool,
+) -> Result<(), Error> {
+    let entry = HistoryEntry {
+        timestamp: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        succeeded,
+        manifest_uri: summary.manifest_uri.clone(),
+        patched: summary.patched.clone(),
+        downloaded: summary.downloaded.clone(),
+        failed: summary.failed.iter().map(|(n, _)| n.clone()).collect(),
+        downloads: summary.downloads.clone(),
+        stats: summary.stats.clone(),
+    };
+    let mut line =
+        serde_json::to_string(&entry).map_err(Error::SerializeError)?;
+    line.push('\n');
+
+    let path = history_path(config);
+    let mut history_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|ioe| match ioe.kind() {
+            io::ErrorKind::PermissionDenied => {
+                Error::PermissionDenied(format!("opening {:?}", path), ioe)
+            }
+            _ => Error::UnknownIoError(format!("opening {:?}", path), ioe),
+        })?;
+
+    // A single write, so that the line can't end up interleaved with another
+    // instance's.
+    history_file
+        .write_all(line.as_bytes())
+        .map_err(|ioe| Error::FileWriteError(path, ioe))
+}
+
+/// Displays the `limit` most recent updates in the history, oldest first.
+/// Lines of the history file that can't be understood are skipped.
+pub fn display(config: &Config, limit: usize) -> Result<(), Error> {
+    let path = history_path(config);
+    let history_file = match OpenOptions::new().read(true).open(&path) {
+        Ok(f) => f,
+        Err(ioe) if ioe.kind() == io::ErrorKind::NotFound => {
+            println!("No updates have been recorded yet.");
+
+            return Ok(());
+        }
+        Err(ioe) => {
+            return Err(Error::UnknownIoError(
+                format!("opening {:?}", path),
+                ioe,
+            ))
+        }
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(history_file).lines() {
+        let line =
+            line.map_err(|ioe| Error::FileReadError(path.clone(), ioe))?;
+        if let Ok(entry) = serde_json::from_str::<HistoryEntry>(&line) {
+            entries.push(entry);
+        }
+    }
+    if entries.is_empty() {
+        println!("No updates have been recorded yet.");
+
+        return Ok(());
+    }
+
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        println!(
+            "{}  {}",
+            httpdate::fmt_http_date(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(entry.timestamp),
+            ),
+            if entry.succeeded {
+                "succeeded"
+            } else {
+                "FAILED"
+            },
+        );
+        print_stats(
+            "    ",
+            &entry.stats,
+            entry.patched.len(),
+            entry.downloaded.len(),
+            entry.failed.len(),
+        );
+        for file_name in entry.failed.iter() {
+            println!("    Failed to update {}", file_name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Displays the statistics of an update that just happened.
+pub fn print_report(summary: &UpdateSummary) {
+    println!("Update summary:");
+    print_stats(
+        "    ",
+        &summary.stats,
+        summary.patched.len(),
+        summary.downloaded.len(),
+        summary.failed.len(),
+    );
+    let stats = &summary.stats;
+    println!(
+        "    Time: {:.1}s manifest, {:.1}s checking, {:.1}s downloading, \
+         {:.1}s extracting, {:.1}s patching",
+        stats.manifest_secs,
+        stats.checking_secs,
+        stats.downloading_secs,
+        stats.extracting_secs,
+        stats.patching_secs,
+    );
+}
+
+fn print_stats(
+    indent: &str,
+    stats: &UpdateStats,
+    patched: usize,
+    downloaded: usize,
+    failed: usize,
+) {
+    println!(
+        "{}{} file(s) checked: {} up to date, {} patched, {} downloaded, {} \
+         failed; {} skipped, {} held",
+        indent,
+        stats.files_checked,
+        stats.files_up_to_date,
+        patched,
+        downloaded,
+        failed,
+        stats.files_skipped,
+        stats.files_held,
+    );
+
+    println!(
+        "{}Downloaded {}: {} of full downloads, {} of patches",
+        indent,
+        format_bytes(stats.bytes_downloaded),
+        format_bytes(stats.bytes_full_download),
+        format_bytes(stats.bytes_patches),
+    );
+    // Patched files are only known by their uncompressed size, so what
+    // patching saved can't be put any more exactly than this.
+    if stats.bytes_patched_estimate > stats.bytes_patches {
+        println!(
+            "{}Patched {} of files, saving at most {:.0}% over downloading \
+             them in full",
+            indent,
+            format_bytes(stats.bytes_patched_estimate),
+            100.0
+                * (stats.bytes_patched_estimate - stats.bytes_patches) as f64
+                / stats.bytes_patched_estimate as f64,
+        );
+    }
+
+    println!(
+        "{}{} retry(s), {:.1}s in total",
+        indent, stats.retries, stats.total_secs,
+    );
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+
+    let mut scaled = n as f64 / 1024.0;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", scaled, UNITS[unit])
+}
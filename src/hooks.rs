@@ -0,0 +1,155 @@
+use crate::{config::Config, error::Error, update::UpdateSummary, util};
+use std::{
+    io::{BufWriter, Write},
+    path::PathBuf,
+    process,
+};
+
+/// Runs the configured pre-update hook, if any. The hook runs after the
+/// manifest has been downloaded, but before any files have been touched; if
+/// it fails, the update is aborted.
+pub fn run_pre_update(
+    config: &Config,
+    quiet: bool,
+    manifest: &serde_json::Value,
+    summary: &UpdateSummary,
+) -> Result<(), Error> {
+    let command = if let Some(c) = &config.hooks.pre_update {
+        c
+    } else {
+        return Ok(());
+    };
+
+    if !quiet {
+        println!("Running pre-update hook...");
+    }
+
+    let status =
+        run_hook(config, "pre-update", command, manifest, summary, None)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::PreUpdateHookFailed(command.clone(), status.code()))
+    }
+}
+
+/// Runs the configured post-update hook, if any. The update has already
+/// happened (or failed) by this point, so failure of the hook itself is only
+/// reported, rather than treated as an error.
+pub fn run_post_update(
+    config: &Config,
+    quiet: bool,
+    manifest: &serde_json::Value,
+    summary: &UpdateSummary,
+    succeeded: bool,
+) {
+    let command = if let Some(c) = &config.hooks.post_update {
+        c
+    } else {
+        return;
+    };
+
+    if !quiet {
+        println!("Running post-update hook...");
+    }
+
+    match run_hook(
+        config,
+        "post-update",
+        command,
+        manifest,
+        summary,
+        Some(succeeded),
+    ) {
+        Ok(status) if !status.success() => {
+            if let Some(code) = status.code() {
+                eprintln!("Post-update hook exited with status {}", code);
+            } else {
+                eprintln!("Post-update hook was killed by a signal");
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+        _ => (),
+    }
+}
+
+/// The hook is run by the platform's shell, in the installation directory,
+/// with the following environment variables set:
+///
+/// - `SBU_HOOK`: `pre-update` or `post-update`.
+/// - `SBU_INSTALL_DIR`, `SBU_CACHE_DIR`: as in the config.
+/// - `SBU_MANIFEST`: path to a copy of the manifest that is being updated to.
+/// - `SBU_MANIFEST_URI`: the mirror that the manifest came from.
+/// - `SBU_PATCHED`, `SBU_DOWNLOADED`, `SBU_FAILED`: newline-separated names
+///   of files that were patched, downloaded in full, or failed to update.
+///   Always empty for the pre-update hook.
+/// - `SBU_UPDATE_RESULT`: `success` or `failure`. Only set for the
+///   post-update hook.
+fn run_hook(
+    config: &Config,
+    hook_name: &str,
+    command: &str,
+    manifest: &serde_json::Value,
+    summary: &UpdateSummary,
+    succeeded: Option<bool>,
+) -> Result<process::ExitStatus, Error> {
+    let manifest_path = write_manifest_copy(config, manifest)?;
+
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = process::Command::new("cmd");
+        cmd.arg("/C").arg(command);
+
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c").arg(command);
+
+        cmd
+    };
+
+    cmd.current_dir(&config.install_dir)
+        .env("SBU_HOOK", hook_name)
+        .env("SBU_INSTALL_DIR", &config.install_dir)
+        .env("SBU_CACHE_DIR", &config.cache_dir)
+        .env("SBU_MANIFEST", &manifest_path)
+        .env("SBU_MANIFEST_URI", &summary.manifest_uri)
+        .env("SBU_PATCHED", summary.patched.join("\n"))
+        .env("SBU_DOWNLOADED", summary.downloaded.join("\n"))
+        .env(
+            "SBU_FAILED",
+            summary
+                .failed
+                .iter()
+                .map(|(n, _)| n.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+    if let Some(succeeded) = succeeded {
+        cmd.env(
+            "SBU_UPDATE_RESULT",
+            if succeeded { "success" } else { "failure" },
+        );
+    }
+
+    cmd.status()
+        .map_err(|ioe| Error::HookSpawnError(command.to_owned(), ioe))
+}
+
+fn write_manifest_copy(
+    config: &Config,
+    manifest: &serde_json::Value,
+) -> Result<PathBuf, Error> {
+    let manifest_path = config.cache_dir.join("manifest.json");
+
+    let mut writer = BufWriter::new(util::create_file(&manifest_path)?);
+    serde_json::to_writer(&mut writer, manifest)
+        .map_err(Error::SerializeError)?;
+    writer
+        .flush()
+        .map_err(|ioe| Error::FileWriteError(manifest_path.clone(), ioe))?;
+
+    Ok(manifest_path)
+}
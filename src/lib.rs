@@ -0,0 +1,28 @@
+//! Everything that the `shticker_book_unwritten` launcher does, other than
+//! its command line interface: reading configs, updating a TTR installation
+//! from the manifest and CDN (including applying bsdiff patches), logging in
+//! to TTR so that the game can be launched, and keeping saved passwords in an
+//! encrypted vault.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+#![deny(deprecated)]
+
+pub mod config;
+pub mod credential;
+pub mod error;
+pub mod history;
+mod hooks;
+pub mod login;
+#[cfg(feature = "mock-server")]
+pub mod mock;
+mod multifile;
+pub mod net;
+pub mod packs;
+pub mod patch;
+pub mod retry;
+pub mod totp;
+mod transport;
+pub mod update;
+mod util;
+pub mod vault;
@@ -1,197 +1,70 @@
+//! Logging in to TTR through its login API, which results in the play cookie
+//! and game server that the game has to be launched with.
+
 use crate::{
-    config::{commit_config, Config},
+    config::Config,
     error::Error,
+    retry::{self, RetryPolicy},
 };
 use reqwest::{blocking as rb, header};
-use rpassword;
 use serde::Serialize;
-use std::{
-    collections::BTreeMap,
-    ffi::OsStr,
-    io::{self, Write},
-    path::Path,
-    process, thread,
-    time::{Duration, Instant},
-};
-
-const LOGIN_API_URI: &str =
-    "https://www.toontownrewritten.com/api/login?format=json";
-
-pub fn login<'a, P: AsRef<Path>, A: Iterator<Item = &'a str>>(
-    config: &mut Config,
-    config_path: P,
-    client: &rb::Client,
-    quiet: bool,
-    argv: A,
-    children: &mut Vec<(String, process::Child, Instant)>,
-) -> Result<(), Error> {
-    let (mut usernames, mut no_save) = (Vec::new(), false);
-    for arg in argv {
-        match arg {
-            "-n" | "--no-save" => no_save = true,
-            _ => usernames.push(arg),
-        }
-    }
-
-    let mut username_buf = String::new();
-
-    if !usernames.is_empty() {
-        for username in usernames {
-            if let Some(password) = config
-                .accounts
-                .get(username)
-                .and_then(|val| {
-                    if let serde_json::Value::String(p) = val {
-                        if !quiet {
-                            println!("Using saved password...");
-                        }
-
-                        Some(p)
-                    } else {
-                        None
-                    }
-                })
-                .cloned()
-            {
-                handle_name_and_pw(
-                    config,
-                    config_path.as_ref(),
-                    client,
-                    quiet,
-                    no_save,
-                    username.to_owned(),
-                    password,
-                )?
-                .map(|c| children.push(c));
-            } else {
-                print!("Password for {}: ", username);
-                io::stdout().flush().map_err(Error::StdoutError)?;
-
-                handle_name_and_pw(
-                    config,
-                    config_path.as_ref(),
-                    client,
-                    quiet,
-                    no_save,
-                    username.to_owned(),
-                    rpassword::read_password_from_tty(None)
-                        .map_err(Error::PasswordReadError)?,
-                )?
-                .map(|c| children.push(c));
-            }
-        }
-    } else {
-        print!("Username: ");
-        io::stdout().flush().map_err(Error::StdoutError)?;
-        username_buf.reserve(0x10);
-        io::stdin()
-            .read_line(&mut username_buf)
-            .map_err(Error::StdinError)?;
-        username_buf.truncate(username_buf.trim_end().len());
-
-        let password = if let Some(password) =
-            config.accounts.get(&username_buf).and_then(|val| {
-                if let serde_json::Value::String(p) = val {
-                    if !quiet {
-                        println!("Using saved password...");
-                    }
+use std::{collections::BTreeMap, process, thread, time::Duration};
 
-                    Some(p)
-                } else {
-                    None
-                }
-            }) {
-            password.clone()
-        } else {
-            print!("Password for {}: ", username_buf);
-            io::stdout().flush().map_err(Error::StdoutError)?;
+/// What a successful login results in: everything needed to launch the game.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub play_cookie: String,
+    pub game_server: String,
+}
 
-            rpassword::read_password_from_tty(None)
-                .map_err(Error::PasswordReadError)?
-        };
+/// How a login attempt ended, if it didn't end in an error.
+#[derive(Debug, Clone)]
+pub enum LoginOutcome {
+    Success(Session),
+    /// The login API turned us down, with this explanation.
+    Failed(String),
+    /// The login was cancelled by the `LoginHandler`.
+    Cancelled,
+}
 
-        handle_name_and_pw(
-            config,
-            config_path,
-            client,
-            quiet,
-            no_save,
-            username_buf,
-            password,
-        )?
-        .map(|c| children.push(c));
+/// Whatever has to be done on the client's end over the course of logging
+/// in, other than talking to the login API.
+pub trait LoginHandler {
+    /// Called when two-factor authentication is needed. `banner` is what the
+    /// login API says about it. Returning `Ok(None)` cancels the login.
+    fn two_factor_token(
+        &mut self,
+        banner: &str,
+    ) -> Result<Option<String>, Error>;
+
+    /// Called each time that the login API tells us to wait in its queue,
+    /// with our position in line, if the login API says what it is.
+    fn queued(
+        &mut self,
+        _eta: f64,
+        _position: Option<u64>,
+    ) -> Result<(), Error> {
+        Ok(())
     }
-
-    Ok(())
 }
 
-fn handle_name_and_pw<P: AsRef<Path>>(
-    config: &mut Config,
-    config_path: P,
+/// Logs in to TTR with the given credentials, going through the queue and
+/// two-factor authentication of the login API at `login_api_uri` (with the
+/// help of `handler`) as necessary.
+pub fn negotiate<H: LoginHandler>(
     client: &rb::Client,
-    quiet: bool,
-    no_save: bool,
-    username: String,
-    password: String,
-) -> Result<Option<(String, process::Child, Instant)>, Error> {
+    retry_policy: &RetryPolicy,
+    login_api_uri: &str,
+    username: &str,
+    password: &str,
+    handler: &mut H,
+) -> Result<LoginOutcome, Error> {
     let mut params = BTreeMap::new();
-    params.insert("username", username.as_str());
-    params.insert("password", password.as_str());
-    if let Some(response_json) = handle_login_negotiation(
-        client,
-        quiet,
-        post_to_login_api(client, &params)?,
-    )? {
-        if !no_save {
-            let old_acc = config.add_account(username.clone(), password);
-            commit_config(config, config_path)?;
-            if !quiet && old_acc.is_none() {
-                println!("New account saved in config!");
-            }
-        }
+    params.insert("username", username);
+    params.insert("password", password);
+    let mut response_json =
+        post_to_login_api(client, retry_policy, login_api_uri, &params)?;
 
-        let play_cookie = response_json
-            .get("cookie")
-            .and_then(|val| {
-                if let serde_json::Value::String(c) = val {
-                    Some(c)
-                } else {
-                    None
-                }
-            })
-            .ok_or(Error::BadLoginResponse(
-                "Expected \"cookie\" key with String value",
-            ))?;
-        let game_server = response_json
-            .get("gameserver")
-            .and_then(|val| {
-                if let serde_json::Value::String(gs) = val {
-                    Some(gs)
-                } else {
-                    None
-                }
-            })
-            .ok_or(Error::BadLoginResponse(
-                "Expected \"gameserver\" key with String value",
-            ))?;
-
-        let ret = launch(config, quiet, play_cookie, game_server)
-            .map(|c| Some((username, c, Instant::now())));
-        if !quiet && ret.is_ok() {
-            println!("Game launched successfully!");
-        }
-
-        ret
-    } else {
-        Ok(None)
-    }
-}
-
-fn handle_login_negotiation(
-    client: &rb::Client,
-    quiet: bool,
-    mut response_json: serde_json::Value,
-) -> Result<Option<serde_json::Value>, Error> {
     loop {
         let success = response_json
             .get("success")
@@ -212,41 +85,40 @@ fn handle_login_negotiation(
 
         match success {
             "true" => {
-                if !quiet {
-                    println!("Authentication success!");
-                }
-
-                return Ok(Some(response_json));
+                return session(&response_json).map(LoginOutcome::Success)
             }
             "delayed" => {
-                response_json = enqueue(client, quiet, &response_json)?
+                response_json = enqueue(
+                    client,
+                    retry_policy,
+                    login_api_uri,
+                    handler,
+                    &response_json,
+                )?
             }
             "partial" => {
-                response_json =
-                    if let Some(rj) = do_2fa(client, &response_json)? {
-                        rj
-                    } else {
-                        return Ok(None);
-                    }
+                response_json = if let Some(rj) = do_2fa(
+                    client,
+                    retry_policy,
+                    login_api_uri,
+                    handler,
+                    &response_json,
+                )? {
+                    rj
+                } else {
+                    return Ok(LoginOutcome::Cancelled);
+                }
             }
             "false" => {
-                println!(
-                    "Login failed: {}",
+                return Ok(LoginOutcome::Failed(
                     response_json
                         .get("banner")
-                        .and_then(|val| {
-                            if let serde_json::Value::String(s) = val {
-                                Some(s)
-                            } else {
-                                None
-                            }
-                        })
+                        .and_then(serde_json::Value::as_str)
                         .ok_or(Error::BadLoginResponse(
                             "Expected \"banner\" key with String value",
-                        ))?,
-                );
-
-                return Ok(None);
+                        ))?
+                        .to_owned(),
+                ))
             }
             _ => {
                 return Err(Error::UnexpectedSuccessValue(success.to_owned()))
@@ -255,58 +127,100 @@ fn handle_login_negotiation(
     }
 }
 
-/// Return value is `Ok(None)` if cancelled by user.
-fn do_2fa(
+/// Launches the game, already logged in to `session`.
+pub fn launch(
+    config: &Config,
+    session: &Session,
+) -> Result<process::Child, Error> {
+    #[cfg(target_os = "linux")]
+    let command_text = "./TTREngine";
+    #[cfg(windows)]
+    let command_text = {
+        // `.current_dir(&config.install_dir)` doesn't seem to work like it
+        // does on Linux, so this is just a (naive) way of making real sure
+        // that we are pointing at the right executable.
+        let mut command_buf = config.install_dir.clone();
+        command_buf.push("TTREngine.exe");
+
+        command_buf
+    };
+    #[cfg(target_os = "macos")]
+    let command_text = {
+        // `.current_dir` is also borked on macOS.
+        let mut command_buf = config.install_dir.clone();
+        command_buf.push("Toontown Rewritten");
+
+        command_buf
+    };
+
+    process::Command::new(command_text)
+        .current_dir(&config.install_dir)
+        .env("TTR_PLAYCOOKIE", &session.play_cookie)
+        .env("TTR_GAMESERVER", &session.game_server)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .map_err(Error::ThreadSpawnError)
+}
+
+fn session(response_json: &serde_json::Value) -> Result<Session, Error> {
+    Ok(Session {
+        play_cookie: response_json
+            .get("cookie")
+            .and_then(serde_json::Value::as_str)
+            .ok_or(Error::BadLoginResponse(
+                "Expected \"cookie\" key with String value",
+            ))?
+            .to_owned(),
+        game_server: response_json
+            .get("gameserver")
+            .and_then(serde_json::Value::as_str)
+            .ok_or(Error::BadLoginResponse(
+                "Expected \"gameserver\" key with String value",
+            ))?
+            .to_owned(),
+    })
+}
+
+/// Return value is `Ok(None)` if cancelled by `handler`.
+fn do_2fa<H: LoginHandler>(
     client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    login_api_uri: &str,
+    handler: &mut H,
     response_json: &serde_json::Value,
 ) -> Result<Option<serde_json::Value>, Error> {
     let auth_token = response_json
         .get("responseToken")
-        .and_then(|val| {
-            if let serde_json::Value::String(s) = val {
-                Some(s)
-            } else {
-                None
-            }
-        })
+        .and_then(serde_json::Value::as_str)
         .ok_or(Error::BadLoginResponse(
             "Expected \"responseToken\" key with String value",
         ))?;
+    let banner = response_json
+        .get("banner")
+        .and_then(serde_json::Value::as_str)
+        .ok_or(Error::BadLoginResponse(
+            "Expected \"banner\" key with String value",
+        ))?;
 
-    print!(
-        "{}\n(...or type \"cancel\" to cancel):\n> ",
-        response_json
-            .get("banner")
-            .and_then(|val| if let serde_json::Value::String(s) = val {
-                Some(s)
-            } else {
-                None
-            })
-            .ok_or(Error::BadLoginResponse(
-                "Expected \"banner\" key with String value",
-            ))?,
-    );
-    io::stdout().flush().map_err(Error::StdoutError)?;
-    let mut app_token = String::with_capacity(0x10);
-    io::stdin()
-        .read_line(&mut app_token)
-        .map_err(Error::StdinError)?;
-    app_token.truncate(app_token.trim_end().len());
-
-    if app_token == "cancel" {
-        Ok(None)
-    } else {
+    if let Some(app_token) = handler.two_factor_token(banner)? {
         let mut params = BTreeMap::new();
         params.insert("appToken", app_token.as_str());
         params.insert("authToken", auth_token);
 
-        post_to_login_api(client, &params).map(Some)
+        post_to_login_api(client, retry_policy, login_api_uri, &params)
+            .map(Some)
+    } else {
+        Ok(None)
     }
 }
 
-fn enqueue(
+fn enqueue<H: LoginHandler>(
     client: &rb::Client,
-    quiet: bool,
+    retry_policy: &RetryPolicy,
+    login_api_uri: &str,
+    handler: &mut H,
     response_json: &serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     let eta = response_json
@@ -319,33 +233,16 @@ fn enqueue(
         .ok_or(Error::BadLoginResponse(
             "Expected \"eta\" key with a String or Number value",
         ))?;
-    if !quiet {
-        println!(
-            "Waiting in queue... ETA: {}, position in line: {}",
-            eta,
-            response_json
-                .get("position")
-                .and_then(|val| match val {
-                    serde_json::Value::String(s) => s.parse().ok(),
-                    serde_json::Value::Number(n) => n.as_u64(),
-                    _ => None,
-                })
-                .ok_or(Error::BadLoginResponse(
-                    "Expected \"position\" key with a String or unsigned \
-                     Number value",
-                ))?,
-        );
-    }
+    let position = response_json.get("position").and_then(|val| match val {
+        serde_json::Value::String(s) => s.parse().ok(),
+        serde_json::Value::Number(n) => n.as_u64(),
+        _ => None,
+    });
+    handler.queued(eta, position)?;
 
     let queue_token = response_json
         .get("queueToken")
-        .and_then(|val| {
-            if let serde_json::Value::String(s) = val {
-                Some(s)
-            } else {
-                None
-            }
-        })
+        .and_then(serde_json::Value::as_str)
         .ok_or(Error::BadLoginResponse(
             "Expected \"queueToken\" key with a String value",
         ))?;
@@ -363,64 +260,30 @@ fn enqueue(
     let mut params = BTreeMap::new();
     params.insert("queueToken", queue_token);
 
-    post_to_login_api(client, &params)
+    post_to_login_api(client, retry_policy, login_api_uri, &params)
 }
 
 fn post_to_login_api<K: Ord + Serialize, V: Serialize>(
     client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    login_api_uri: &str,
     params: &BTreeMap<K, V>,
 ) -> Result<serde_json::Value, Error> {
-    serde_json::from_str(
-        &client
-            .post(LOGIN_API_URI)
+    retry_policy.run("", |_| {
+        let resp = client
+            .post(login_api_uri)
             .header(header::ACCEPT, "text/plain")
             .form(&params)
             .send()
-            .map_err(Error::PostError)?
-            .text()
-            .map_err(Error::PostError)?,
-    )
-    .map_err(Error::DeserializeError)
-}
-
-fn launch<S: AsRef<OsStr>, T: AsRef<OsStr>>(
-    config: &Config,
-    quiet: bool,
-    play_cookie: S,
-    game_server: T,
-) -> Result<process::Child, Error> {
-    if !quiet {
-        println!("Launching the game...");
-    }
-
-    #[cfg(target_os = "linux")]
-    let command_text = "./TTREngine";
-    #[cfg(windows)]
-    let command_text = {
-        // `.current_dir(&config.install_dir)` doesn't seem to work like it
-        // does on Linux, so this is just a (naive) way of making real sure
-        // that we are pointing at the right executable.
-        let mut command_buf = config.install_dir.clone();
-        command_buf.push("TTREngine.exe");
-
-        command_buf
-    };
-    #[cfg(target_os = "macos")]
-    let command_text = {
-        // `.current_dir` is also borked on macOS.
-        let mut command_buf = config.install_dir.clone();
-        command_buf.push("Toontown Rewritten");
-
-        command_buf
-    };
+            .map_err(Error::PostError)?;
+        if !resp.status().is_success() {
+            return Err(Error::PostStatusError(
+                resp.status(),
+                retry::retry_after(&resp),
+            ));
+        }
 
-    process::Command::new(&command_text)
-        .current_dir(&config.install_dir)
-        .env("TTR_PLAYCOOKIE", play_cookie)
-        .env("TTR_GAMESERVER", game_server)
-        .stdin(process::Stdio::null())
-        .stdout(process::Stdio::null())
-        .stderr(process::Stdio::null())
-        .spawn()
-        .map_err(Error::ThreadSpawnError)
+        serde_json::from_str(&resp.text().map_err(Error::PostError)?)
+            .map_err(Error::DeserializeError)
+    })
 }
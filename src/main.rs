@@ -2,20 +2,31 @@
 #![deny(clippy::all)]
 #![deny(deprecated)]
 
+mod check;
 mod command;
-mod config;
-mod error;
-mod login;
-mod patch;
-mod update;
-mod util;
+mod play;
+mod setup;
+mod watch;
 
 use clap::{
     crate_authors, crate_description, crate_name, crate_version, App, Arg,
+    ArgMatches,
 };
+use config::IpFamily;
 use error::Error;
-use reqwest::blocking as rb;
-use std::{num::NonZeroUsize, process};
+use play::{SecretSource, Secrets};
+use retry::RetryPolicy;
+use shticker_book_unwritten::{
+    config, credential, error, history, login, net, packs, patch, retry, totp,
+    update, vault,
+};
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
+use update::FileFilter;
 
 fn main() {
     if let Err(e) = run() {
@@ -48,7 +59,7 @@ fn run() -> Result<(), Error> {
         "/config.json",
     );
 
-    let arg_matches = App::new(crate_name!())
+    let app = App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
@@ -123,13 +134,127 @@ fn run() -> Result<(), Error> {
                     "If this option is supplied, then after (possibly) \
                      auto-updating, the game will be launched with these \
                      username(s). The password(s) will be prompted for as \
-                     normal if they aren't saved. Then, if the login(s) \
+                     normal if they aren't saved (or supplied by one of the \
+                     --password-* options). Then, if the login(s) \
                      succeed, command mode is entered (assuming -d is not \
                      supplied).",
                 )
                 .takes_value(true)
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("password-env")
+                .long("password-env")
+                .value_name("VAR")
+                .help("Read passwords from the environment variable VAR.")
+                .long_help(
+                    "Instead of using saved passwords or prompting for them, \
+                     use the value of the environment variable VAR as the \
+                     password for every login. Passwords that are supplied \
+                     this way (or by any of the other --password-* options) \
+                     are never saved.",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "password-stdin",
+                    "password-file",
+                    "password-fd",
+                ]),
+        )
+        .arg(
+            Arg::with_name("password-stdin")
+                .long("password-stdin")
+                .help("Read passwords from stdin, one per line.")
+                .long_help(
+                    "Instead of using saved passwords or prompting for them, \
+                     read a line from stdin for each login, as it is needed. \
+                     If --2fa-stdin is also supplied, the two-factor tokens \
+                     are read from stdin too, in whatever order that they are \
+                     needed in.",
+                )
+                .takes_value(false)
+                .conflicts_with_all(&["password-file", "password-fd"]),
+        )
+        .arg(
+            Arg::with_name("password-file")
+                .long("password-file")
+                .value_name("PATH")
+                .help("Read passwords from the file at PATH, one per line.")
+                .long_help(
+                    "Instead of using saved passwords or prompting for them, \
+                     use the lines of the file at PATH as the passwords for \
+                     each login, in order. The whole file is read at \
+                     startup, so it can be a named pipe.",
+                )
+                .takes_value(true)
+                .conflicts_with("password-fd"),
+        )
+        .arg(
+            Arg::with_name("2fa-env")
+                .long("2fa-env")
+                .value_name("VAR")
+                .help(
+                    "Read two-factor tokens from the environment variable \
+                     VAR.",
+                )
+                .long_help(
+                    "Instead of prompting for two-factor authentication \
+                     tokens, use the value of the environment variable VAR \
+                     as the token for every login that needs one.",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&["2fa-stdin", "2fa-file", "2fa-fd"]),
+        )
+        .arg(
+            Arg::with_name("2fa-stdin")
+                .long("2fa-stdin")
+                .help("Read two-factor tokens from stdin, one per line.")
+                .takes_value(false)
+                .conflicts_with_all(&["2fa-file", "2fa-fd"]),
+        )
+        .arg(
+            Arg::with_name("2fa-file")
+                .long("2fa-file")
+                .value_name("PATH")
+                .help(
+                    "Read two-factor tokens from the file at PATH, one per \
+                     line.",
+                )
+                .takes_value(true)
+                .conflicts_with("2fa-fd"),
+        )
+        .arg(
+            Arg::with_name("vault-password-env")
+                .long("vault-password-env")
+                .value_name("VAR")
+                .help(
+                    "Read the vault's master password from the environment \
+                     variable VAR.",
+                )
+                .long_help(
+                    "Instead of prompting for the password vault's master \
+                     password, use the value of the environment variable \
+                     VAR. This lets saved passwords and TOTP secrets be used \
+                     without a TTY. There is only one attempt at unlocking \
+                     the vault this way.",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "vault-password-file",
+                    "vault-password-fd",
+                ]),
+        )
+        .arg(
+            Arg::with_name("vault-password-file")
+                .long("vault-password-file")
+                .value_name("PATH")
+                .help(
+                    "Read the vault's master password from the first line of \
+                     the file at PATH.",
+                )
+                .takes_value(true)
+                .conflicts_with("vault-password-fd"),
+        )
         .arg(
             Arg::with_name("detach")
                 .short("d")
@@ -170,8 +295,11 @@ fn run() -> Result<(), Error> {
                 .long_help(
                     "Positive integer number of times to try doing things \
                      that involve interacting with the network. Defaults to \
-                     5. Currently works for downloading files, including the \
-                     manifest.",
+                     5. Applies to downloading files (including the \
+                     manifest) and to talking to the login API. Only \
+                     transient failures (timeouts, dropped connections, \
+                     server errors, corrupted downloads) are retried, with \
+                     exponential backoff between attempts.",
                 )
                 .takes_value(true),
         )
@@ -190,18 +318,272 @@ fn run() -> Result<(), Error> {
                 .takes_value(false)
                 .conflicts_with("no-auto-update"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .value_name("GLOB")
+                .help("Only update manifest files matching GLOB.")
+                .long_help(
+                    "Only update the manifest files whose names match GLOB \
+                     (e.g. \"phase_3*.mf\"). May be given more than once. \
+                     Replaces any include patterns found in the config, but \
+                     will not be written to the config.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .help("Hold back manifest files matching GLOB.")
+                .long_help(
+                    "Never update the manifest files whose names match GLOB; \
+                     they are reported as held instead. May be given more \
+                     than once. Adds to any exclude patterns found in the \
+                     config, but will not be written to the config.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Keep updating the other files when one fails.")
+                .long_help(
+                    "When a file fails to update, keep going with the rest \
+                     of the files, and report all of the failures at the \
+                     end. This is the default for --watch and --watch-once, \
+                     and can also be set with \"keep_going\" in the \
+                     config.",
+                )
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("stop-on-error")
+                .long("stop-on-error")
+                .help("Stop updating as soon as any file fails.")
+                .long_help(
+                    "Stop updating as soon as any file fails to update. This \
+                     is the default, except for --watch and --watch-once.",
+                )
+                .takes_value(false)
+                .conflicts_with("keep-going"),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("PROXY_URI")
+                .help("HTTP(S) or SOCKS5 proxy to send all requests through.")
+                .long_help(
+                    "URI of a proxy to send all requests (manifest, CDN, and \
+                     login) through. The scheme may be http://, https://, \
+                     socks5://, or socks5h:// (SOCKS5 with DNS resolved by \
+                     the proxy). Overrides the value found in \
+                     the config (if any), but will not be written to the \
+                     config.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ca-cert")
+                .long("ca-cert")
+                .value_name("PEM_FILE")
+                .help("Extra root CA certificate(s) to trust.")
+                .long_help(
+                    "PEM file containing one or more root CA certificates to \
+                     trust in addition to the usual ones, e.g. for proxies \
+                     that intercept TLS. May be given more than once. These \
+                     are used in addition to any found in the config.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("SECONDS")
+                .help("Timeout for establishing connections.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-timeout")
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .help("Timeout for receiving responses.")
+                .long_help(
+                    "Timeout for receiving a response, and for each read of \
+                     the response's body. Defaults to 30 seconds.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("user-agent")
+                .long("user-agent")
+                .value_name("USER_AGENT")
+                .help("User-Agent header to send with all requests.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ipv4")
+                .short("4")
+                .long("ipv4")
+                .help("Only connect over IPv4.")
+                .takes_value(false)
+                .conflicts_with("ipv6"),
+        )
+        .arg(
+            Arg::with_name("ipv6")
+                .short("6")
+                .long("ipv6")
+                .help("Only connect over IPv6.")
+                .takes_value(false)
+                .conflicts_with("ipv4"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .short("w")
+                .long("watch")
+                .value_name("MINUTES")
+                .help("Keep checking for updates every MINUTES minutes.")
+                .long_help(
+                    "Instead of entering command mode, keep running and \
+                     check for updates every MINUTES minutes, applying them \
+                     as soon as no game instances are running. With -y, \
+                     available updates are only reported. Any usernames \
+                     supplied with -u are logged in after the first check.",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&["no-auto-update", "detach"]),
+        )
+        .arg(
+            Arg::with_name("watch-once")
+                .long("watch-once")
+                .help("Check for updates once, as --watch would, then exit.")
+                .long_help(
+                    "Like --watch, but only checks once before exiting. If \
+                     updates are available but the game is running, this \
+                     waits for it to exit before updating.",
+                )
+                .takes_value(false)
+                .conflicts_with_all(&["watch", "no-auto-update", "detach"]),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help(
+                    "Silently check for updates, and exit with a status \
+                     code describing the result.",
+                )
+                .long_help(
+                    "Checks for updates without downloading or changing \
+                     anything, printing nothing (unless --json is supplied), \
+                     and exits with one of the following status codes:\n\n  \
+                     0    Everything is up to date.\n  \
+                     100  Updates are available.\n  \
+                     101  The manifest couldn't be downloaded.\n  \
+                     102  Some local file doesn't match any version known to \
+                     the manifest, and would have to be downloaded from \
+                     scratch.\n\n\
+                     Any other nonzero status code means that some other \
+                     error occurred.",
+                )
+                .takes_value(false)
+                .conflicts_with_all(&[
+                    "no-auto-update",
+                    "dry-update",
+                    "username",
+                    "detach",
+                    "watch",
+                    "watch-once",
+                ]),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("With --check, print a JSON summary to stdout.")
+                .takes_value(false)
+                .requires("check"),
+        );
+    #[cfg(target_os = "linux")]
+    let app = app.arg(
+        Arg::with_name("systemd-units")
+            .long("systemd-units")
+            .value_name("UNIT_DIR")
+            .help(
+                "Write a systemd user service & timer that check for \
+                 updates, then exit.",
+            )
+            .long_help(
+                "Writes a systemd user service that runs --watch-once, and a \
+                 timer that runs said service every MINUTES minutes (as \
+                 given by --watch, or 60 by default), into UNIT_DIR (usually \
+                 ~/.config/systemd/user). The current config file, and \
+                 --tries, are used by the service.",
+            )
+            .takes_value(true),
+    );
+    #[cfg(unix)]
+    let app = app
+        .arg(
+            Arg::with_name("password-fd")
+                .long("password-fd")
+                .value_name("FD")
+                .help(
+                    "Read passwords from the inherited file descriptor FD, \
+                     one per line.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vault-password-fd")
+                .long("vault-password-fd")
+                .value_name("FD")
+                .help(
+                    "Read the vault's master password from the first line of \
+                     the inherited file descriptor FD.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("2fa-fd")
+                .long("2fa-fd")
+                .value_name("FD")
+                .help(
+                    "Read two-factor tokens from the inherited file \
+                     descriptor FD, one per line.",
+                )
+                .takes_value(true),
+        );
+    let arg_matches = app.get_matches();
 
-    let quiet = arg_matches.is_present("quiet");
-    let max_tries = if let Some(tries_str) = arg_matches.value_of("tries") {
-        tries_str
-            .parse()
-            .map_err(|_| Error::InvalidArgValue("--tries/-t"))?
-    } else {
-        NonZeroUsize::new(5).unwrap()
-    };
+    let check = arg_matches.is_present("check");
+    let quiet = arg_matches.is_present("quiet") || check;
+    let retry_policy = RetryPolicy::new(
+        if let Some(tries_str) = arg_matches.value_of("tries") {
+            tries_str
+                .parse()
+                .map_err(|_| Error::InvalidArgValue("--tries/-t"))?
+        } else {
+            NonZeroUsize::new(5).unwrap()
+        },
+    );
+
+    let watch_interval =
+        if let Some(minutes_str) = arg_matches.value_of("watch") {
+            Some(
+                minutes_str
+                    .parse::<NonZeroU64>()
+                    .map_err(|_| Error::InvalidArgValue("--watch/-w"))?,
+            )
+        } else {
+            None
+        };
 
-    let (mut config, config_path) = config::get_config(
+    let (mut config, config_path) = setup::get_config(
         arg_matches.is_present("no-config"),
         arg_matches.value_of("config"),
         arg_matches.value_of("install-dir"),
@@ -209,17 +591,118 @@ fn run() -> Result<(), Error> {
         quiet,
     )?;
 
-    let client = rb::ClientBuilder::new()
-        .build()
-        .map_err(Error::HttpClientCreateError)?;
+    let mut secrets = Secrets {
+        vault: None,
+        passwords: secret_source(&arg_matches, "password", "--password-fd")?,
+        two_factor_tokens: secret_source(&arg_matches, "2fa", "--2fa-fd")?,
+        master_passwords: secret_source(
+            &arg_matches,
+            "vault-password",
+            "--vault-password-fd",
+        )?,
+    };
+
+    let mut network = config.network.clone();
+    if let Some(proxy) = arg_matches.value_of("proxy") {
+        network.proxy = Some(proxy.to_owned());
+    }
+    if let Some(ca_certs) = arg_matches.values_of("ca-cert") {
+        network.ca_certs.extend(ca_certs.map(PathBuf::from));
+    }
+    if let Some(secs) = arg_matches.value_of("connect-timeout") {
+        network.connect_timeout_secs = Some(
+            secs.parse()
+                .map_err(|_| Error::InvalidArgValue("--connect-timeout"))?,
+        );
+    }
+    if let Some(secs) = arg_matches.value_of("read-timeout") {
+        network.read_timeout_secs = Some(
+            secs.parse()
+                .map_err(|_| Error::InvalidArgValue("--read-timeout"))?,
+        );
+    }
+    if let Some(user_agent) = arg_matches.value_of("user-agent") {
+        network.user_agent = Some(user_agent.to_owned());
+    }
+    if arg_matches.is_present("ipv4") {
+        network.ip_family = Some(IpFamily::Ipv4);
+    } else if arg_matches.is_present("ipv6") {
+        network.ip_family = Some(IpFamily::Ipv6);
+    }
+
+    let client = net::build_client(&network)?;
+
+    let mut filter = FileFilter::from_config(&config);
+    if let Some(include) = arg_matches.values_of("include") {
+        filter.include = include.map(str::to_owned).collect();
+    }
+    if let Some(exclude) = arg_matches.values_of("exclude") {
+        filter.exclude.extend(exclude.map(str::to_owned));
+    }
+
+    // Nobody is around to deal with a failure in the background, so it
+    // might as well get as much done as it can.
+    let background =
+        watch_interval.is_some() || arg_matches.is_present("watch-once");
+    let keep_going = if arg_matches.is_present("keep-going") {
+        true
+    } else if arg_matches.is_present("stop-on-error") {
+        false
+    } else {
+        config.keep_going.unwrap_or(background)
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(unit_dir) = arg_matches.value_of("systemd-units") {
+            return watch::write_systemd_units(
+                unit_dir,
+                &config,
+                &config_path,
+                retry_policy.max_tries,
+                watch_interval.map(NonZeroU64::get).unwrap_or(60),
+                quiet,
+            );
+        }
+    }
+
+    if check {
+        process::exit(check::check(
+            &config,
+            &client,
+            &retry_policy,
+            &filter,
+            keep_going,
+            arg_matches.is_present("json"),
+        )?);
+    }
+
+    if watch_interval.is_some() || arg_matches.is_present("watch-once") {
+        return watch::watch(
+            &mut config,
+            &config_path,
+            &client,
+            &retry_policy,
+            &filter,
+            keep_going,
+            quiet,
+            arg_matches.is_present("dry-update"),
+            watch_interval
+                .map(|minutes| Duration::from_secs(minutes.get() * 60)),
+            arg_matches.values_of("username"),
+            &mut secrets,
+        );
+    }
 
     if !arg_matches.is_present("no-auto-update") {
         update::update(
             &config,
             &client,
             quiet,
-            max_tries,
+            &retry_policy,
             arg_matches.is_present("dry-update"),
+            &filter,
+            keep_going,
         )?;
 
         if !quiet {
@@ -234,6 +717,39 @@ fn run() -> Result<(), Error> {
         quiet,
         arg_matches.values_of("username"),
         arg_matches.is_present("detach"),
-        max_tries,
+        &retry_policy,
+        &filter,
+        keep_going,
+        &mut secrets,
     )
 }
+
+/// The source of passwords (or of two-factor tokens, for `secret` ==
+/// `"2fa"`, or of the vault's master password, for `secret` ==
+/// `"vault-password"`) that was supplied on the command line, if any.
+fn secret_source(
+    arg_matches: &ArgMatches,
+    secret: &str,
+    #[cfg_attr(not(unix), allow(unused_variables))] fd_flag: &'static str,
+) -> Result<Option<SecretSource>, Error> {
+    if let Some(var) = arg_matches.value_of(format!("{}-env", secret)) {
+        return SecretSource::env(var).map(Some);
+    }
+    if arg_matches.is_present(format!("{}-stdin", secret)) {
+        return Ok(Some(SecretSource::Stdin));
+    }
+    if let Some(path) = arg_matches.value_of(format!("{}-file", secret)) {
+        return SecretSource::file(path).map(Some);
+    }
+    #[cfg(unix)]
+    {
+        if let Some(fd) = arg_matches.value_of(format!("{}-fd", secret)) {
+            return SecretSource::fd(
+                fd.parse().map_err(|_| Error::InvalidArgValue(fd_flag))?,
+            )
+            .map(Some);
+        }
+    }
+
+    Ok(None)
+}
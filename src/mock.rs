@@ -0,0 +1,393 @@
+//! A stand-in for TTR's servers, for testing on machines without internet
+//! access. It serves the files in a directory (so that a manifest and CDN
+//! files can be laid out in it however the config's URIs expect), and answers
+//! POSTs to `/api/login` like the login API does, including its queue and
+//! two-factor authentication (with fixed tokens, or TOTP).
+
+use crate::totp;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The path that the mock login API is at.
+pub const LOGIN_API_PATH: &str = "/api/login";
+/// What the mock login API hands out as the game server.
+pub const MOCK_GAME_SERVER: &str = "127.0.0.1:7198";
+
+/// An account that the mock login API knows about.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MockAccount {
+    pub password: String,
+    /// If set, logging in also requires this two-factor token.
+    #[serde(default)]
+    pub toonguard: Option<String>,
+    /// If set, logging in also requires the current TOTP token for this
+    /// (base32-encoded) secret.
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    /// How many times the login has to be retried from the queue before it
+    /// goes through.
+    #[serde(default)]
+    pub queue: u64,
+}
+
+/// Where a login is at, as remembered between requests.
+enum Pending {
+    TwoFactor { username: String },
+    Queued { username: String, position: u64 },
+}
+
+struct State {
+    root: PathBuf,
+    accounts: BTreeMap<String, MockAccount>,
+    pending: HashMap<String, Pending>,
+    next_token: u64,
+}
+
+/// A running mock server.
+pub struct MockServer {
+    addr: SocketAddr,
+}
+
+impl MockServer {
+    /// Starts serving `root` and the login API (knowing about `accounts`) on
+    /// `addr`, on a background thread.
+    pub fn start<A: Into<SocketAddr>, P: Into<PathBuf>>(
+        addr: A,
+        root: P,
+        accounts: BTreeMap<String, MockAccount>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr.into())?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            root: root.into(),
+            accounts,
+            pending: HashMap::new(),
+            next_token: 0,
+        }));
+
+        thread::Builder::new().spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&state);
+                let _ = thread::Builder::new().spawn(move || {
+                    if let Err(ioe) = handle_connection(&state, stream) {
+                        eprintln!("Mock server connection failed: {}", ioe);
+                    }
+                });
+            }
+        })?;
+
+        Ok(Self { addr })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The HTTP URI of `path` on this server.
+    pub fn uri(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+}
+
+fn handle_connection(
+    state: &Mutex<State>,
+    stream: TcpStream,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(m), Some(t)) => (m.to_owned(), t.to_owned()),
+        _ => return respond(stream, 400, "text/plain", b"Bad request", true),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let path = target.split('?').next().unwrap_or("");
+    match method.as_str() {
+        "POST" if path == LOGIN_API_PATH => {
+            let params = parse_form(&String::from_utf8_lossy(&body));
+            let response = {
+                let mut state =
+                    state.lock().unwrap_or_else(PoisonError::into_inner);
+                login(&mut state, &params)
+            };
+
+            respond(
+                stream,
+                200,
+                "application/json",
+                response.to_string().as_bytes(),
+                true,
+            )
+        }
+        "GET" | "HEAD" => {
+            let file_path = {
+                let state =
+                    state.lock().unwrap_or_else(PoisonError::into_inner);
+                served_path(&state.root, path)
+            };
+
+            match file_path.and_then(|p| fs::read(p).ok()) {
+                Some(contents) => respond(
+                    stream,
+                    200,
+                    "application/octet-stream",
+                    &contents,
+                    method == "GET",
+                ),
+                None => respond(stream, 404, "text/plain", b"Not found", true),
+            }
+        }
+        _ => respond(stream, 405, "text/plain", b"Method not allowed", true),
+    }
+}
+
+/// The file under `root` that `path` refers to, if `path` doesn't try to
+/// escape from `root`.
+fn served_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        Some(root.join(relative))
+    } else {
+        None
+    }
+}
+
+fn respond(
+    mut stream: TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+    send_body: bool,
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len(),
+    )?;
+    if send_body {
+        stream.write_all(body)?;
+    }
+
+    stream.flush()
+}
+
+/// Answers a POST to the login API the way that TTR's does.
+fn login(
+    state: &mut State,
+    params: &HashMap<String, String>,
+) -> serde_json::Value {
+    let get = |key: &str| params.get(key).map(String::as_str);
+
+    let username = match (
+        get("username"),
+        get("password"),
+        get("appToken"),
+        get("authToken"),
+        get("queueToken"),
+    ) {
+        (Some(username), Some(password), _, _, _) => {
+            match state.accounts.get(username) {
+                Some(account) if account.password == password => {
+                    if account.toonguard.is_some()
+                        || account.totp_secret.is_some()
+                    {
+                        let token = new_token(state);
+                        state.pending.insert(
+                            token.clone(),
+                            Pending::TwoFactor {
+                                username: username.to_owned(),
+                            },
+                        );
+
+                        return json!({
+                            "success": "partial",
+                            "banner": "Please enter your ToonGuard code.",
+                            "responseToken": token,
+                        });
+                    }
+
+                    username.to_owned()
+                }
+                _ => return failure("Incorrect username and/or password."),
+            }
+        }
+        (_, _, Some(app_token), Some(auth_token), _) => {
+            match state.pending.remove(auth_token) {
+                Some(Pending::TwoFactor { username })
+                    if state
+                        .accounts
+                        .get(&username)
+                        .is_some_and(|a| accepts_token(a, app_token)) =>
+                {
+                    username
+                }
+                Some(Pending::TwoFactor { .. }) => {
+                    return failure("Incorrect ToonGuard code.")
+                }
+                _ => return failure("Your login session has expired."),
+            }
+        }
+        (_, _, _, _, Some(queue_token)) => {
+            match state.pending.remove(queue_token) {
+                Some(Pending::Queued { username, position }) => {
+                    if position == 0 {
+                        return success(&username);
+                    }
+
+                    return queued(state, username, position - 1);
+                }
+                _ => return failure("Your login session has expired."),
+            }
+        }
+        _ => return failure("Missing login parameters."),
+    };
+
+    let queue = state.accounts.get(&username).map_or(0, |a| a.queue);
+    if queue > 0 {
+        queued(state, username, queue - 1)
+    } else {
+        success(&username)
+    }
+}
+
+/// Whether `token` is a valid two-factor token for `account`. TOTP tokens
+/// from the previous period are accepted too, in case of clock drift.
+fn accepts_token(account: &MockAccount, token: &str) -> bool {
+    if account.toonguard.as_deref() == Some(token) {
+        return true;
+    }
+
+    match account
+        .totp_secret
+        .as_deref()
+        .and_then(|s| totp::decode_secret(s).ok())
+    {
+        Some(secret) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            token == totp::token_at(&secret, now)
+                || token
+                    == totp::token_at(
+                        &secret,
+                        now.saturating_sub(totp::PERIOD),
+                    )
+        }
+        None => false,
+    }
+}
+
+fn queued(
+    state: &mut State,
+    username: String,
+    position: u64,
+) -> serde_json::Value {
+    let token = new_token(state);
+    state
+        .pending
+        .insert(token.clone(), Pending::Queued { username, position });
+
+    json!({
+        "success": "delayed",
+        "eta": "0",
+        "position": position.to_string(),
+        "queueToken": token,
+    })
+}
+
+fn success(username: &str) -> serde_json::Value {
+    json!({
+        "success": "true",
+        "cookie": format!("mock-cookie-{}", username),
+        "gameserver": MOCK_GAME_SERVER,
+    })
+}
+
+fn failure(banner: &str) -> serde_json::Value {
+    json!({ "success": "false", "banner": banner })
+}
+
+fn new_token(state: &mut State) -> String {
+    state.next_token += 1;
+
+    format!("mock-token-{}", state.next_token)
+}
+
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,137 @@
+//! Just enough of Panda3D's multifile (`.mf`) format to find out what is
+//! inside of one, which is what both the game's phase files and content
+//! packs are.
+
+use crate::{error::Error, util};
+use std::{
+    io::{prelude::*, BufReader, SeekFrom},
+    path::Path,
+};
+
+const MAGIC: &[u8; 6] = b"pmf\0\n\r";
+
+const SF_DELETED: u16 = 0x0001;
+const SF_COMPRESSED: u16 = 0x0008;
+const SF_ENCRYPTED: u16 = 0x0010;
+const SF_SIGNATURE: u16 = 0x0020;
+
+/// An entry in a multifile's index.
+#[derive(Debug, Clone)]
+pub struct Subfile {
+    pub name: String,
+    /// Offset of the subfile's (possibly compressed) data, from the start of
+    /// the multifile.
+    pub data_start: u64,
+    pub data_length: u64,
+}
+
+/// Reads the index of the multifile at `path`. Deleted subfiles and
+/// signatures are left out.
+pub fn read_index<P: AsRef<Path>>(path: P) -> Result<Vec<Subfile>, Error> {
+    let path = path.as_ref();
+    let bad = |msg| Error::BadMultifile(path.to_path_buf(), msg);
+    let mut r = BufReader::new(util::open_file(path)?);
+
+    // Multifiles may start with any number of `#` comment lines, e.g. a
+    // shebang, which all offsets are relative to the end of.
+    let mut offset = 0;
+    let mut line = Vec::new();
+    loop {
+        if r.fill_buf()
+            .map_err(|ioe| Error::FileReadError(path.to_path_buf(), ioe))?
+            .first()
+            != Some(&b'#')
+        {
+            break;
+        }
+
+        line.clear();
+        offset += r
+            .read_until(b'\n', &mut line)
+            .map_err(|ioe| Error::FileReadError(path.to_path_buf(), ioe))?
+            as u64;
+    }
+
+    let mut magic = [0u8; 6];
+    read_exact(&mut r, path, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(bad("not a multifile"));
+    }
+    let _major_ver = read_u16(&mut r, path)?;
+    let minor_ver = read_u16(&mut r, path)?;
+    let scale_factor = u64::from(read_u32(&mut r, path)?);
+    if scale_factor == 0 {
+        return Err(bad("scale factor of zero"));
+    }
+    let mut pos = 6 + 2 + 2 + 4;
+    if minor_ver >= 1 {
+        let _timestamp = read_u32(&mut r, path)?;
+        pos += 4;
+    }
+
+    // The first entry starts at the first scaled address after the header.
+    let mut next_index =
+        pos + (scale_factor - pos % scale_factor) % scale_factor;
+    let mut subfiles = Vec::new();
+    loop {
+        r.seek(SeekFrom::Start(offset + next_index))
+            .map_err(|ioe| Error::SeekError(path.to_path_buf(), ioe))?;
+
+        let following = u64::from(read_u32(&mut r, path)?) * scale_factor;
+        if following == 0 {
+            break;
+        } else if following <= next_index {
+            return Err(bad("index entries out of order"));
+        }
+        next_index = following;
+
+        let data_start = u64::from(read_u32(&mut r, path)?) * scale_factor;
+        let data_length = u64::from(read_u32(&mut r, path)?);
+        let flags = read_u16(&mut r, path)?;
+        if flags & (SF_COMPRESSED | SF_ENCRYPTED) != 0 {
+            let _uncompressed_length = read_u32(&mut r, path)?;
+        }
+        if minor_ver >= 1 {
+            let _timestamp = read_u32(&mut r, path)?;
+        }
+        let name_length = usize::from(read_u16(&mut r, path)?);
+        let mut name = vec![0u8; name_length];
+        read_exact(&mut r, path, &mut name)?;
+        for b in name.iter_mut() {
+            *b ^= 0xff;
+        }
+
+        if flags & (SF_DELETED | SF_SIGNATURE) == 0 {
+            subfiles.push(Subfile {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                data_start: offset + data_start,
+                data_length,
+            });
+        }
+    }
+
+    Ok(subfiles)
+}
+
+fn read_exact<R: Read>(
+    r: &mut R,
+    path: &Path,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    r.read_exact(buf)
+        .map_err(|ioe| Error::FileReadError(path.to_path_buf(), ioe))
+}
+
+fn read_u16<R: Read>(r: &mut R, path: &Path) -> Result<u16, Error> {
+    let mut buf = [0u8; 2];
+    read_exact(r, path, &mut buf)?;
+
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R, path: &Path) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    read_exact(r, path, &mut buf)?;
+
+    Ok(u32::from_le_bytes(buf))
+}
@@ -0,0 +1,81 @@
+use crate::{
+    config::{IpFamily, NetworkConfig},
+    error::Error,
+    util,
+};
+use reqwest::{blocking as rb, Certificate, Proxy};
+use std::{
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+const PEM_CERT_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERT_END: &str = "-----END CERTIFICATE-----";
+
+pub fn build_client(network: &NetworkConfig) -> Result<rb::Client, Error> {
+    let mut builder = rb::ClientBuilder::new();
+
+    if let Some(proxy_uri) = &network.proxy {
+        builder = builder.proxy(
+            Proxy::all(proxy_uri)
+                .map_err(|re| Error::BadProxy(proxy_uri.clone(), re))?,
+        );
+    }
+
+    for ca_cert_path in network.ca_certs.iter() {
+        let mut pem = String::new();
+        util::open_file(ca_cert_path)?
+            .read_to_string(&mut pem)
+            .map_err(|ioe| Error::FileReadError(ca_cert_path.clone(), ioe))?;
+
+        // A single PEM file may well be a whole bundle of certificates, but
+        // `Certificate::from_pem` only ever reads the first one.
+        let mut rest = pem.as_str();
+        if !rest.contains(PEM_CERT_BEGIN) {
+            // Let `reqwest` produce a meaningful error
+            Certificate::from_pem(rest.as_bytes()).map_err(|re| {
+                Error::BadCertificate(ca_cert_path.clone(), re)
+            })?;
+        }
+        while let Some(begin) = rest.find(PEM_CERT_BEGIN) {
+            let end = rest[begin..]
+                .find(PEM_CERT_END)
+                .map(|end| begin + end + PEM_CERT_END.len())
+                .unwrap_or_else(|| rest.len());
+
+            builder = builder.add_root_certificate(
+                Certificate::from_pem(&rest.as_bytes()[begin..end]).map_err(
+                    |re| Error::BadCertificate(ca_cert_path.clone(), re),
+                )?,
+            );
+
+            rest = &rest[end..];
+        }
+    }
+
+    if let Some(secs) = network.connect_timeout_secs {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = network.read_timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+
+    if let Some(user_agent) = &network.user_agent {
+        builder = builder.user_agent(user_agent.as_str());
+    }
+
+    // Binding to the unspecified address of one family restricts outgoing
+    // connections to that family.
+    match network.ip_family {
+        Some(IpFamily::Ipv4) => {
+            builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        }
+        Some(IpFamily::Ipv6) => {
+            builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        }
+        None => (),
+    }
+
+    builder.build().map_err(Error::HttpClientCreateError)
+}
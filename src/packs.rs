@@ -0,0 +1,420 @@
+use crate::{
+    config::{commit_config, Config},
+    error::Error,
+    multifile, util,
+};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, prelude::*, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Where the game looks for content packs, relative to the installation
+/// directory.
+const DEPLOY_DIR: &str = "resources/contentpacks";
+/// Marks the packs in `DEPLOY_DIR` that are ours to manage; anything else in
+/// there is left alone.
+const DEPLOYED_PREFIX: &str = "sbu_";
+
+/// Maps the name of each asset that an enabled pack overrides, to the names
+/// of the packs that override it.
+pub(crate) type Overrides = BTreeMap<String, Vec<String>>;
+
+/// Where installed packs are kept, whether or not they are enabled.
+pub(crate) fn packs_dir(config: &Config) -> PathBuf {
+    config
+        .packs
+        .dir
+        .clone()
+        .unwrap_or_else(|| config.cache_dir.join("packs"))
+}
+
+/// Whether `file_name`, as found in the manifest, would land among the
+/// deployed content packs. The updater never touches such files.
+pub(crate) fn is_pack_path(file_name: &str) -> bool {
+    Path::new(file_name).starts_with(DEPLOY_DIR)
+}
+
+/// Handles the `packs` command, with `argv` being its arguments.
+pub fn command<'a, P: AsRef<Path>, A: Iterator<Item = &'a str>>(
+    config: &mut Config,
+    config_path: P,
+    quiet: bool,
+    mut argv: A,
+) -> Result<(), Error> {
+    match (argv.next(), argv.next(), argv.next()) {
+        (None, _, _) | (Some("list"), None, _) => list(config),
+        (Some("install"), Some(path), None) => install(config, quiet, path),
+        (Some("enable"), Some(name), None) => {
+            enable(config, config_path, quiet, name)
+        }
+        (Some("disable"), Some(name), None) => {
+            disable(config, config_path, quiet, name)
+        }
+        (Some("order"), Some(name), Some(position)) => {
+            if let Ok(position) = position.parse() {
+                set_order(config, config_path, quiet, name, position)
+            } else {
+                println!("Expected a position, starting at 1!");
+
+                Ok(())
+            }
+        }
+        _ => {
+            println!(
+                "Usage: packs [list | install <path> | enable <pack> | \
+                 disable <pack> | order <pack> <position>]"
+            );
+
+            Ok(())
+        }
+    }
+}
+
+fn list(config: &Config) -> Result<(), Error> {
+    let installed = installed_packs(config)?;
+    if installed.is_empty() && config.packs.enabled.is_empty() {
+        println!("No content packs are installed.");
+
+        return Ok(());
+    }
+
+    for (i, name) in config.packs.enabled.iter().enumerate() {
+        if installed.contains(name) {
+            println!("{:3}. {}", i + 1, name);
+        } else {
+            println!("{:3}. {} (missing!)", i + 1, name);
+        }
+    }
+    for name in installed
+        .iter()
+        .filter(|name| !config.packs.enabled.contains(name))
+    {
+        println!("     {} (disabled)", name);
+    }
+
+    Ok(())
+}
+
+/// Copies the pack at `path` into the packs directory, replacing any pack of
+/// the same name.
+fn install(config: &Config, quiet: bool, path: &str) -> Result<(), Error> {
+    let path = Path::new(path);
+    let name = if let Some(n) = path.file_name().and_then(|n| n.to_str()) {
+        n.to_owned()
+    } else {
+        println!("Expected a path to a content pack file!");
+
+        return Ok(());
+    };
+
+    // Make sure that it's really a content pack, before going any further.
+    let subfiles = match multifile::read_index(path) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}", e);
+
+            return Ok(());
+        }
+    };
+
+    let dir = packs_dir(config);
+    fs::create_dir_all(&dir)
+        .map_err(|ioe| Error::MkdirError(dir.clone(), ioe))?;
+    let installed_path = dir.join(&name);
+    fs::copy(path, &installed_path).map_err(|ioe| {
+        Error::UnknownIoError(
+            format!("copying {:?} to {:?}", path, installed_path),
+            ioe,
+        )
+    })?;
+
+    if !quiet {
+        println!(
+            "Installed {}, which overrides {} asset(s).",
+            name,
+            subfiles.len(),
+        );
+    }
+
+    if config.packs.enabled.contains(&name) {
+        deploy(config, quiet)
+    } else {
+        if !quiet {
+            println!("Enable it with: packs enable {}", name);
+        }
+
+        Ok(())
+    }
+}
+
+fn enable<P: AsRef<Path>>(
+    config: &mut Config,
+    config_path: P,
+    quiet: bool,
+    name: &str,
+) -> Result<(), Error> {
+    if !installed_packs(config)?.iter().any(|n| n == name) {
+        println!("No content pack named {} is installed!", name);
+
+        return Ok(());
+    }
+    if config.packs.enabled.iter().any(|n| n == name) {
+        println!("{} is already enabled.", name);
+
+        return Ok(());
+    }
+
+    config.packs.enabled.push(name.to_owned());
+    save(config, config_path)?;
+
+    deploy(config, quiet)
+}
+
+fn disable<P: AsRef<Path>>(
+    config: &mut Config,
+    config_path: P,
+    quiet: bool,
+    name: &str,
+) -> Result<(), Error> {
+    let len_before = config.packs.enabled.len();
+    config.packs.enabled.retain(|n| n != name);
+    if config.packs.enabled.len() == len_before {
+        println!("{} isn't enabled.", name);
+
+        return Ok(());
+    }
+    save(config, config_path)?;
+
+    deploy(config, quiet)
+}
+
+/// Moves an enabled pack to the 1-indexed `position` in the load order.
+fn set_order<P: AsRef<Path>>(
+    config: &mut Config,
+    config_path: P,
+    quiet: bool,
+    name: &str,
+    position: usize,
+) -> Result<(), Error> {
+    let i =
+        if let Some(i) = config.packs.enabled.iter().position(|n| n == name) {
+            i
+        } else {
+            println!("{} isn't enabled.", name);
+
+            return Ok(());
+        };
+
+    let name = config.packs.enabled.remove(i);
+    let position = position.max(1).min(config.packs.enabled.len() + 1);
+    config.packs.enabled.insert(position - 1, name);
+    save(config, config_path)?;
+
+    deploy(config, quiet)
+}
+
+fn save<P: AsRef<Path>>(config: &Config, config_path: P) -> Result<(), Error> {
+    // With `--no-config`, changes only last for as long as we're running.
+    if config_path.as_ref().as_os_str().is_empty() {
+        Ok(())
+    } else {
+        commit_config(config, config_path)
+    }
+}
+
+/// Names of the packs in the packs directory, in alphabetical order.
+fn installed_packs(config: &Config) -> Result<Vec<String>, Error> {
+    let dir = packs_dir(config);
+    let rd = match fs::read_dir(&dir) {
+        Ok(rd) => rd,
+        Err(ioe) if ioe.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(ioe) => {
+            return Err(Error::UnknownIoError(
+                format!("reading the directory {:?}", dir),
+                ioe,
+            ))
+        }
+    };
+
+    let mut names = Vec::new();
+    for entry in rd {
+        let entry = entry.map_err(|ioe| {
+            Error::UnknownIoError(
+                format!("reading the directory {:?}", dir),
+                ioe,
+            )
+        })?;
+        if let Ok(name) = entry.file_name().into_string() {
+            if entry.path().is_file() {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+/// Makes the game's content pack directory reflect the enabled packs: any
+/// packs that we previously put there are removed, and then the enabled ones
+/// are put there with names that sort in the configured order.
+fn deploy(config: &Config, quiet: bool) -> Result<(), Error> {
+    let deploy_dir = config.install_dir.join(DEPLOY_DIR);
+    fs::create_dir_all(&deploy_dir)
+        .map_err(|ioe| Error::MkdirError(deploy_dir.clone(), ioe))?;
+
+    let rd = fs::read_dir(&deploy_dir).map_err(|ioe| {
+        Error::UnknownIoError(
+            format!("reading the directory {:?}", deploy_dir),
+            ioe,
+        )
+    })?;
+    for entry in rd {
+        let entry = entry.map_err(|ioe| {
+            Error::UnknownIoError(
+                format!("reading the directory {:?}", deploy_dir),
+                ioe,
+            )
+        })?;
+        let ours = entry
+            .file_name()
+            .to_str()
+            .map(|n| n.starts_with(DEPLOYED_PREFIX))
+            .unwrap_or(false);
+        if ours {
+            let path = entry.path();
+            fs::remove_file(&path)
+                .map_err(|ioe| Error::RemoveFileError(path, ioe))?;
+        }
+    }
+
+    let packs_dir = packs_dir(config);
+    for (i, name) in config.packs.enabled.iter().enumerate() {
+        let from = packs_dir.join(name);
+        if !from.is_file() {
+            eprintln!("Enabled content pack {} is missing, skipping...", name);
+
+            continue;
+        }
+
+        let to = deploy_dir.join(format!(
+            "{}{:02}_{}",
+            DEPLOYED_PREFIX,
+            i + 1,
+            name
+        ));
+        // Hard links save space, but they don't work across filesystems.
+        if fs::hard_link(&from, &to).is_err() {
+            let temp = util::temp_path(&to);
+            fs::copy(&from, &temp).map_err(|ioe| {
+                Error::UnknownIoError(
+                    format!("copying {:?} to {:?}", from, temp),
+                    ioe,
+                )
+            })?;
+            // Syncing needs write access on some platforms.
+            let copied = fs::OpenOptions::new()
+                .write(true)
+                .open(&temp)
+                .map_err(|ioe| Error::FileWriteError(temp.clone(), ioe))?;
+            util::sync_file(&copied, &temp)?;
+            util::rename_into_place(&temp, &to)?;
+        }
+    }
+
+    if !quiet {
+        println!(
+            "{} content pack(s) are now enabled.",
+            config.packs.enabled.len(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Reads which assets the enabled packs override. Packs that can't be read
+/// are reported and then ignored.
+pub(crate) fn overrides(config: &Config) -> Overrides {
+    let packs_dir = packs_dir(config);
+    let mut overrides = Overrides::new();
+
+    for name in config.packs.enabled.iter() {
+        match multifile::read_index(packs_dir.join(name)) {
+            Ok(subfiles) => {
+                for subfile in subfiles {
+                    overrides
+                        .entry(subfile.name)
+                        .or_default()
+                        .push(name.clone());
+                }
+            }
+            Err(e) => eprintln!("Unable to read content pack {}: {}", name, e),
+        }
+    }
+
+    overrides
+}
+
+/// Hashes each of the assets in the multifile at `path` that are overridden
+/// by a pack, so that what an update does to them can be compared. If `path`
+/// isn't a readable multifile, no assets are returned.
+pub(crate) fn overridden_digests<P: AsRef<Path>>(
+    path: P,
+    overrides: &Overrides,
+) -> BTreeMap<String, [u8; 20]> {
+    let mut digests = BTreeMap::new();
+    if overrides.is_empty() {
+        return digests;
+    }
+
+    let subfiles = if let Ok(s) = multifile::read_index(&path) {
+        s
+    } else {
+        return digests;
+    };
+    let mut file = if let Ok(f) = util::open_file(&path) {
+        f
+    } else {
+        return digests;
+    };
+
+    for subfile in subfiles {
+        if !overrides.contains_key(&subfile.name) {
+            continue;
+        }
+
+        let mut sha = Sha1::default();
+        let hashed =
+            file.seek(SeekFrom::Start(subfile.data_start))
+                .and_then(|_| {
+                    io::copy(
+                        &mut (&mut file).take(subfile.data_length),
+                        &mut sha,
+                    )
+                });
+        if hashed.is_ok() {
+            digests.insert(subfile.name, sha.result().into());
+        }
+    }
+
+    digests
+}
+
+/// Lists the overridden assets that differ between `before` and `after`,
+/// as returned by `overridden_digests`.
+pub(crate) fn changed_assets(
+    before: &BTreeMap<String, [u8; 20]>,
+    after: &BTreeMap<String, [u8; 20]>,
+) -> Vec<String> {
+    before
+        .keys()
+        .chain(after.keys().filter(|k| !before.contains_key(*k)))
+        .filter(|k| before.get(*k) != after.get(*k))
+        .cloned()
+        .collect()
+}
@@ -1,7 +1,11 @@
 //! The code in this module has been adapted more or less line-for-line from
 //! the source of bsdiff 4.3, which is written in C and is here "oxidized" on a
 //! very basic level, even retaining the original variable names, and is not
-//! (yet) idiomatic or optimized or anything like that.
+//! (yet) idiomatic or optimized or anything like that. The exceptions are
+//! that patches are applied in a streaming fashion, rather than with both the
+//! old and new files entirely in memory, that the patch is decoded on
+//! separate threads, and that patches in the ENDSLEY/BSDIFF43 format are
+//! understood as well.
 //!
 //! bsdiff4 is licensed under a slight variation of the FreeBSD license, which
 //! requires that the licensing text be reproduced alongside any modified or
@@ -39,32 +43,176 @@
 use crate::{error::Error, util};
 use bzip2::read::BzDecoder as BzReadDecoder;
 use std::{
-    self,
-    ffi::{OsStr, OsString},
-    io::{prelude::*, Seek, SeekFrom},
+    fmt, fs,
+    io::{self, prelude::*, BufWriter, Seek, SeekFrom},
     path::Path,
+    sync::{mpsc, Mutex, PoisonError},
+    thread,
 };
 
-pub fn patch_file<P: AsRef<Path>, Q: AsRef<Path>>(
+/// How much of the old and new files are worked on at a time.
+const CHUNK_SIZE: usize = 0x1_00_00;
+/// How many decoded chunks of each block may be waiting to be used at once.
+const DECODED_CHUNKS: usize = 4;
+
+const BSDIFF40_MAGIC: &[u8; 8] = b"BSDIFF40";
+const ENDSLEY_MAGIC: &[u8; 16] = b"ENDSLEY/BSDIFF43";
+
+/// Where the control, diff, and extra blocks of a patch are read from.
+enum Blocks<R> {
+    /// BSDIFF40: each block is its own bzip2 stream.
+    Separate { ctrl: R, diff: R, extra: R },
+    /// ENDSLEY/BSDIFF43: the blocks are interleaved in a single bzip2 stream.
+    Interleaved(R),
+}
+
+type PatchBlocks<'a, R> = Blocks<BzReadDecoder<Section<'a, R>>>;
+
+impl<R: Read> Blocks<R> {
+    fn ctrl(&mut self) -> &mut R {
+        match self {
+            Self::Separate { ctrl, .. } => ctrl,
+            Self::Interleaved(r) => r,
+        }
+    }
+
+    fn diff(&mut self) -> &mut R {
+        match self {
+            Self::Separate { diff, .. } => diff,
+            Self::Interleaved(r) => r,
+        }
+    }
+
+    fn extra(&mut self) -> &mut R {
+        match self {
+            Self::Separate { extra, .. } => extra,
+            Self::Interleaved(r) => r,
+        }
+    }
+}
+
+/// A patch that several `Section`s read from at once, each at its own
+/// position.
+struct Shared<R> {
+    inner: R,
+    /// Where `inner` is currently at, to avoid needless seeks
+    pos: u64,
+}
+
+impl<R: Seek> Shared<R> {
+    fn new(mut inner: R) -> Result<Self, Error> {
+        let pos = inner.stream_position().map_err(Error::PatchReadError)?;
+
+        Ok(Self { inner, pos })
+    }
+
+    fn total_len(&mut self) -> Result<u64, Error> {
+        self.pos = self
+            .inner
+            .seek(SeekFrom::End(0))
+            .map_err(Error::PatchReadError)?;
+
+        Ok(self.pos)
+    }
+}
+
+/// Reads a `Shared` patch from `pos` onwards, regardless of where any other
+/// `Section`s have left it.
+struct Section<'a, R> {
+    src: &'a Mutex<Shared<R>>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for Section<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // `pos` is never wrong, even if some other reader panicked
+        let mut src = self.src.lock().unwrap_or_else(PoisonError::into_inner);
+        if src.pos != self.pos {
+            // In case seeking or reading fails part of the way through
+            src.pos = u64::MAX;
+            src.inner.seek(SeekFrom::Start(self.pos))?;
+        }
+        src.pos = u64::MAX;
+        let n = src.inner.read(buf)?;
+        self.pos += n as u64;
+        src.pos = self.pos;
+
+        Ok(n)
+    }
+}
+
+/// Which flavor of bsdiff a patch was made with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// The original bsdiff 4.x format, with a `BSDIFF40` magic.
+    Bsdiff40,
+    /// Matthew Endsley's format, with an `ENDSLEY/BSDIFF43` magic.
+    Endsley,
+}
+
+impl fmt::Display for PatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bsdiff40 => "BSDIFF40",
+            Self::Endsley => "ENDSLEY/BSDIFF43",
+        })
+    }
+}
+
+/// What a patch's header says about it.
+#[derive(Debug, Clone)]
+pub struct PatchHeader {
+    pub format: PatchFormat,
+    /// Compressed length of the control block. Only BSDIFF40 has one.
+    pub bzctrllen: Option<u64>,
+    /// Compressed length of the diff block. Only BSDIFF40 has one.
+    pub bzdatalen: Option<u64>,
+    /// Size of the file that applying the patch results in.
+    pub new_size: u64,
+}
+
+/// The result of walking through a patch with `inspect`.
+#[derive(Debug, Clone)]
+pub struct PatchInfo {
+    pub header: PatchHeader,
+    /// Number of (x, y, z) triples in the control block.
+    pub ctrl_triples: u64,
+    /// Total number of bytes that are read from the old file.
+    pub old_bytes: u64,
+    /// Total (decompressed) size of the diff block.
+    pub diff_bytes: u64,
+    /// Total (decompressed) size of the extra block.
+    pub extra_bytes: u64,
+    /// The old file has to be at least this big for the patch to make sense.
+    pub min_old_size: u64,
+}
+
+/// Patches `target_file_path` in place. The patched file is written next to
+/// it, and only replaces it once `accept` (which is given the path of the
+/// patched file) returns `Ok`. If patching fails, or `accept` returns an
+/// error, the patched file is removed and the original is left untouched.
+pub fn patch_file<P, Q, F>(
     patch_file_path: P,
     target_file_path: Q,
-) -> Result<(), Error> {
-    let target_file_osstr: &OsStr = target_file_path.as_ref().as_ref();
-    let mut temp_file_path =
-        OsString::with_capacity(target_file_osstr.len() + ".tmp".len());
-    temp_file_path.push(target_file_path.as_ref());
-    temp_file_path.push(".tmp");
-
-    bsdiff_patch(patch_file_path, &target_file_path, &temp_file_path)?;
-
-    std::fs::rename(&temp_file_path, &target_file_path).map_err(|_| {
-        Error::FileRenameError(
-            temp_file_path.into(),
-            target_file_path.as_ref().to_path_buf(),
-        )
-    })?;
+    accept: F,
+) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnOnce(&Path) -> Result<(), Error>,
+{
+    let temp_file_path = util::temp_path(&target_file_path);
+
+    if let Err(e) =
+        bsdiff_patch(patch_file_path, &target_file_path, &temp_file_path)
+            .and_then(|_| accept(&temp_file_path))
+    {
+        let _ = fs::remove_file(&temp_file_path);
 
-    Ok(())
+        return Err(e);
+    }
+
+    util::rename_into_place(&temp_file_path, &target_file_path)
 }
 
 fn bsdiff_patch<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
@@ -72,133 +220,127 @@ fn bsdiff_patch<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
     old_file_path: Q,
     new_file_path: R,
 ) -> Result<(), Error> {
-    let new = apply_patch(patch_file_path, old_file_path)?;
+    let patch_file = util::open_file(&patch_file_path)?;
+    let old_file = util::open_file(&old_file_path)?;
 
-    // Write the new file
-    let mut fd = util::create_file(&new_file_path)?;
-    fd.write_all(&new[..]).map_err(|ioe| {
-        Error::FileWriteError(new_file_path.as_ref().to_path_buf(), ioe)
+    // Write the new file as it's being patched
+    let fd = util::create_file(&new_file_path)?;
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, fd);
+    apply(patch_file, old_file, &mut writer).map_err(|e| {
+        attach_paths(
+            e,
+            patch_file_path.as_ref(),
+            Some(old_file_path.as_ref()),
+            Some(new_file_path.as_ref()),
+        )
+    })?;
+    let fd = writer.into_inner().map_err(|iie| {
+        Error::FileWriteError(
+            new_file_path.as_ref().to_path_buf(),
+            iie.into_error(),
+        )
     })?;
 
-    Ok(())
+    util::sync_file(&fd, &new_file_path)
 }
 
-fn apply_patch<P: AsRef<Path>, Q: AsRef<Path>>(
-    patch_file_path: P,
-    old_file_path: Q,
-) -> Result<Vec<u8>, Error> {
-    let header = {
-        // Open patch file
-        let mut f = util::open_file(&patch_file_path)?;
-
-        /*
-         * File format:
-         *
-         *   | offset | len | data
-         * --+--------+-----+----------------------
-         *   | 0      | 8   | "BSDIFF40"
-         *   | 8      | 8   | X
-         *   | 16     | 8   | Y
-         *   | 24     | 8   | sizeof(new_file)
-         *   | 32     | X   | bzip2(control block)
-         *   | 32+X   | Y   | bzip2(diff block)
-         *   | 32+X+Y | ??? | bzip2(extra block)
-         *
-         * With control block a set of triples (x, y, z) meaning "add x bytes
-         * from old_file to x bytes from the diff block; copy y bytes from the
-         * extra block; seek forwards in old_file by z bytes".
-         */
-
-        // Read header
-        let mut header = [0u8; 32];
-        f.read_exact(&mut header).map_err(|ioe| {
-            Error::FileReadError(patch_file_path.as_ref().to_path_buf(), ioe)
-        })?;
-
-        header
-    };
+/// Applies the bsdiff patch read from `patch` to the old data read from
+/// `old`, writing the new data out to `new`. The patch is read starting from
+/// wherever `patch` currently is.
+///
+/// Unlike the original, which reads the whole old file into memory and
+/// builds the whole new file in memory, this reads `old` with seeks and
+/// writes the new data out to `new` as it goes, `CHUNK_SIZE` bytes at a
+/// time. Also unlike the original, the blocks of the patch are decoded on
+/// their own threads, ahead of when they're needed. The output is the same.
+pub fn apply<P, O, W>(patch: P, old: O, new: &mut W) -> Result<(), Error>
+where
+    P: Read + Seek + Send,
+    O: Read + Seek,
+    W: Write,
+{
+    let patch = Mutex::new(Shared::new(patch)?);
+    let (header, blocks) = open_patch(&patch)?;
 
-    // Check for appropriate magic
-    if header[..8] != b"BSDIFF40"[..] {
-        return Err(Error::BadPatchVersion);
-    }
+    thread::scope(|scope| {
+        let blocks = match blocks {
+            Blocks::Separate { ctrl, diff, extra } => Blocks::Separate {
+                ctrl: decode_in_background(scope, ctrl)?,
+                diff: decode_in_background(scope, diff)?,
+                extra: decode_in_background(scope, extra)?,
+            },
+            Blocks::Interleaved(r) => {
+                Blocks::Interleaved(decode_in_background(scope, r)?)
+            }
+        };
 
-    // Read lengths from header
-    let bzctrllen = offtin(&header[8..]);
-    let bzdatalen = offtin(&header[16..]);
-    let newsize = offtin(&header[24..]);
-    if bzctrllen < 0 || bzdatalen < 0 || newsize < 0 {
-        return Err(Error::BadPatchSize);
-    }
+        // Dropping `blocks` when this returns, even early, is what lets the
+        // decoding threads know to stop
+        apply_blocks(header.new_size as i64, blocks, old, new)
+    })
+}
 
-    // Open patch file in the right places with libbzip2
-    let mut cpf = util::open_file(&patch_file_path)?;
-    cpf.seek(SeekFrom::Start(32)).map_err(|ioe| {
-        Error::SeekError(patch_file_path.as_ref().to_path_buf(), ioe)
-    })?;
-    let mut cpfbz2 = BzReadDecoder::new(cpf);
-    let mut dpf = util::open_file(&patch_file_path)?;
-    dpf.seek(SeekFrom::Start((32 + bzctrllen) as u64))
-        .map_err(|ioe| {
-            Error::SeekError(patch_file_path.as_ref().to_path_buf(), ioe)
-        })?;
-    let mut dpfbz2 = BzReadDecoder::new(dpf);
-    let mut epf = util::open_file(&patch_file_path)?;
-    epf.seek(SeekFrom::Start((32 + bzctrllen + bzdatalen) as u64))
-        .map_err(|ioe| {
-            Error::SeekError(patch_file_path.as_ref().to_path_buf(), ioe)
-        })?;
-    let mut epfbz2 = BzReadDecoder::new(epf);
-
-    let mut fd = util::open_file(&old_file_path)?;
-    let oldsize = fd.seek(SeekFrom::End(0)).map_err(|ioe| {
-        Error::SeekError(old_file_path.as_ref().to_path_buf(), ioe)
-    })? as i64;
-    let mut old = Vec::with_capacity(oldsize as usize);
-    old.resize_with(oldsize as usize, Default::default);
-    fd.seek(SeekFrom::Start(0)).map_err(|ioe| {
-        Error::SeekError(old_file_path.as_ref().to_path_buf(), ioe)
-    })?;
-    fd.read_exact(&mut old[..]).map_err(|ioe| {
-        Error::FileReadError(old_file_path.as_ref().to_path_buf(), ioe)
-    })?;
+fn apply_blocks<R: Read, O: Read + Seek, W: Write>(
+    newsize: i64,
+    mut blocks: Blocks<R>,
+    mut old: O,
+    new: &mut W,
+) -> Result<(), Error> {
+    let oldsize = old
+        .seek(SeekFrom::End(0))
+        .map_err(Error::OldDataReadError)? as i64;
+    // Where `old` is currently at, to avoid needless seeks
+    let mut old_pos = oldsize;
 
-    let mut new = Vec::with_capacity(newsize as usize);
-    new.resize_with(newsize as usize, Default::default);
+    let write_err = Error::NewDataWriteError;
+    let decode_err = Error::PatchDecodeError;
 
     // Start the actual patching
     let mut buf = [0u8; 8];
     let mut ctrl = [0i64; 3];
+    let mut diff_buf = vec![0u8; CHUNK_SIZE];
+    let mut old_buf = vec![0u8; CHUNK_SIZE];
     let mut oldpos = 0i64;
     let mut newpos = 0i64;
     while newpos < newsize {
         // Read control data
         for ctrl_off in ctrl.iter_mut() {
-            cpfbz2.read_exact(&mut buf).map_err(|ioe| {
-                Error::DecodeError(patch_file_path.as_ref().to_path_buf(), ioe)
-            })?;
+            blocks.ctrl().read_exact(&mut buf).map_err(decode_err)?;
             *ctrl_off = offtin(&buf);
         }
 
         // Sanity check
-        if newpos + ctrl[0] > newsize {
+        if ctrl[0] < 0 || newpos + ctrl[0] > newsize {
             return Err(Error::PatchSanityCheckFail(0));
         }
 
-        // Read diff string
-        dpfbz2
-            .read_exact(&mut new[newpos as usize..(newpos + ctrl[0]) as usize])
-            .map_err(|ioe| {
-                Error::DecodeError(patch_file_path.as_ref().to_path_buf(), ioe)
-            })?;
+        // Read diff string, and add old data to it, a chunk at a time
+        let mut done = 0;
+        while done < ctrl[0] {
+            let len = (ctrl[0] - done).min(CHUNK_SIZE as i64);
+            let diff = &mut diff_buf[..len as usize];
+            blocks.diff().read_exact(diff).map_err(decode_err)?;
+
+            // Only the part of this chunk that lies within the old file
+            let lo = (oldpos + done).max(0).min(oldsize);
+            let hi = (oldpos + done + len).max(0).min(oldsize);
+            if lo < hi {
+                let old_chunk = &mut old_buf[..(hi - lo) as usize];
+                if old_pos != lo {
+                    old.seek(SeekFrom::Start(lo as u64))
+                        .map_err(Error::OldDataReadError)?;
+                }
+                old.read_exact(old_chunk).map_err(Error::OldDataReadError)?;
+                old_pos = hi;
 
-        // Add old data to diff string
-        for i in 0..ctrl[0] {
-            if (oldpos + i >= 0) && (oldpos + i < oldsize) {
-                let new_index = (newpos + i) as usize;
-                let old_index = (oldpos + i) as usize;
-                new[new_index] = new[new_index].wrapping_add(old[old_index]);
+                let skip = (lo - (oldpos + done)) as usize;
+                for (d, o) in diff[skip..].iter_mut().zip(old_chunk.iter()) {
+                    *d = d.wrapping_add(*o);
+                }
             }
+
+            new.write_all(diff).map_err(write_err)?;
+            done += len;
         }
 
         // Adjust pointers
@@ -206,23 +348,369 @@ fn apply_patch<P: AsRef<Path>, Q: AsRef<Path>>(
         oldpos += ctrl[0];
 
         // Sanity check
-        if newpos + ctrl[1] > newsize {
+        if ctrl[1] < 0 || newpos + ctrl[1] > newsize {
             return Err(Error::PatchSanityCheckFail(1));
         }
 
-        // Read extra string
-        epfbz2
-            .read_exact(&mut new[newpos as usize..(newpos + ctrl[1]) as usize])
-            .map_err(|ioe| {
-                Error::DecodeError(patch_file_path.as_ref().to_path_buf(), ioe)
-            })?;
+        // Read extra string, a chunk at a time
+        let mut done = 0;
+        while done < ctrl[1] {
+            let len = (ctrl[1] - done).min(CHUNK_SIZE as i64);
+            let extra = &mut diff_buf[..len as usize];
+            blocks.extra().read_exact(extra).map_err(decode_err)?;
+            new.write_all(extra).map_err(write_err)?;
+            done += len;
+        }
 
         // Adjust pointers
         newpos += ctrl[1];
         oldpos += ctrl[2];
     }
 
-    Ok(new)
+    new.flush().map_err(write_err)
+}
+
+/// Decodes `r` on its own thread, a chunk at a time, so that the decoded data
+/// is (hopefully) already waiting by the time that it's read from the
+/// returned reader.
+fn decode_in_background<'scope, R: Read + Send + 'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    mut r: R,
+) -> Result<ChannelReader, Error> {
+    let (tx, rx) = mpsc::sync_channel(DECODED_CHUNKS);
+
+    thread::Builder::new()
+        .spawn_scoped(scope, move || loop {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            let res = match r.read(&mut chunk) {
+                // The end of the block, which closes the channel
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+
+                    Ok(chunk)
+                }
+                Err(ioe) if ioe.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(ioe) => Err(ioe),
+            };
+            let failed = res.is_err();
+
+            // Nobody is listening anymore, so there's no point in going on
+            if tx.send(res).is_err() || failed {
+                break;
+            }
+        })
+        .map_err(Error::ThreadSpawnError)?;
+
+    Ok(ChannelReader {
+        rx,
+        chunk: Vec::new(),
+        pos: 0,
+    })
+}
+
+/// Reads the chunks sent by `decode_in_background`, in order.
+struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    /// How much of `chunk` has already been read
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The decoding thread is done
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+/// Walks through the whole patch at `patch_file_path` without applying it.
+/// See `inspect`.
+pub fn inspect_file<P: AsRef<Path>>(
+    patch_file_path: P,
+) -> Result<PatchInfo, Error> {
+    inspect(util::open_file(&patch_file_path)?)
+        .map_err(|e| attach_paths(e, patch_file_path.as_ref(), None, None))
+}
+
+/// Walks through the whole patch read from `patch` without applying it,
+/// making sure that it's consistent: the control block never seeks before
+/// the start of the old file, nor writes past the end of the new file, and
+/// the diff and extra blocks are exactly as long as the control block says
+/// that they are. This doesn't need the old data, so any patch that fails
+/// this check can be thrown away before anything is touched.
+pub fn inspect<P: Read + Seek>(patch: P) -> Result<PatchInfo, Error> {
+    let patch = Mutex::new(Shared::new(patch)?);
+    let base = patch.lock().unwrap_or_else(PoisonError::into_inner).pos;
+    let (header, mut blocks) = open_patch(&patch)?;
+    let decode_err = Error::PatchDecodeError;
+
+    if let (Some(bzctrllen), Some(bzdatalen)) =
+        (header.bzctrllen, header.bzdatalen)
+    {
+        let patch_len = patch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .total_len()?;
+        let blocks_end = bzctrllen
+            .checked_add(bzdatalen)
+            .and_then(|len| len.checked_add(base + 32));
+        if !matches!(blocks_end, Some(end) if end <= patch_len) {
+            return Err(Error::PatchInconsistent(
+                "header's block lengths run past the end of the patch",
+            ));
+        }
+    }
+
+    let newsize = header.new_size as i64;
+    let mut info = PatchInfo {
+        header,
+        ctrl_triples: 0,
+        old_bytes: 0,
+        diff_bytes: 0,
+        extra_bytes: 0,
+        min_old_size: 0,
+    };
+
+    let mut buf = [0u8; 8];
+    let mut ctrl = [0i64; 3];
+    let mut oldpos = 0i64;
+    let mut newpos = 0i64;
+    while newpos < newsize {
+        for ctrl_off in ctrl.iter_mut() {
+            blocks.ctrl().read_exact(&mut buf).map_err(decode_err)?;
+            *ctrl_off = offtin(&buf);
+        }
+        info.ctrl_triples += 1;
+
+        if ctrl[0] < 0 || newpos + ctrl[0] > newsize {
+            return Err(Error::PatchSanityCheckFail(0));
+        }
+        if ctrl[0] > 0 {
+            if oldpos < 0 {
+                return Err(Error::PatchInconsistent(
+                    "control block seeks before the start of the old file",
+                ));
+            }
+            info.min_old_size =
+                info.min_old_size.max((oldpos + ctrl[0]) as u64);
+        }
+        skip_exactly(blocks.diff(), ctrl[0] as u64).map_err(decode_err)?;
+        info.old_bytes += ctrl[0] as u64;
+        info.diff_bytes += ctrl[0] as u64;
+        newpos += ctrl[0];
+        oldpos = oldpos.checked_add(ctrl[0]).ok_or_else(|| {
+            Error::PatchInconsistent(
+                "control block seeks past any possible old file",
+            )
+        })?;
+
+        if ctrl[1] < 0 || newpos + ctrl[1] > newsize {
+            return Err(Error::PatchSanityCheckFail(1));
+        }
+        skip_exactly(blocks.extra(), ctrl[1] as u64).map_err(decode_err)?;
+        info.extra_bytes += ctrl[1] as u64;
+        newpos += ctrl[1];
+        oldpos = oldpos.checked_add(ctrl[2]).ok_or_else(|| {
+            Error::PatchInconsistent(
+                "control block seeks past any possible old file",
+            )
+        })?;
+    }
+
+    // Nothing should be left over in any of the blocks, and the compressed
+    // blocks should be exactly as long as the header says
+    match &mut blocks {
+        Blocks::Separate { ctrl, diff, extra } => {
+            if !is_exhausted(ctrl).map_err(decode_err)?
+                || !is_exhausted(diff).map_err(decode_err)?
+                || !is_exhausted(extra).map_err(decode_err)?
+            {
+                return Err(Error::PatchInconsistent(
+                    "patch blocks have trailing data",
+                ));
+            }
+            if Some(ctrl.total_in()) != info.header.bzctrllen
+                || Some(diff.total_in()) != info.header.bzdatalen
+            {
+                return Err(Error::PatchInconsistent(
+                    "header's block lengths don't match the blocks",
+                ));
+            }
+        }
+        Blocks::Interleaved(r) => {
+            if !is_exhausted(r).map_err(decode_err)? {
+                return Err(Error::PatchInconsistent(
+                    "patch blocks have trailing data",
+                ));
+            }
+        }
+    }
+
+    Ok(info)
+}
+
+/// Reads the header of the patch read from `patch`, and opens its control,
+/// diff, and extra blocks for decoding.
+fn open_patch<R: Read + Seek>(
+    patch: &Mutex<Shared<R>>,
+) -> Result<(PatchHeader, PatchBlocks<'_, R>), Error> {
+    let base = patch.lock().unwrap_or_else(PoisonError::into_inner).pos;
+    let mut f = Section {
+        src: patch,
+        pos: base,
+    };
+
+    /*
+     * File format:
+     *
+     *   | offset | len | data
+     * --+--------+-----+----------------------
+     *   | 0      | 8   | "BSDIFF40"
+     *   | 8      | 8   | X
+     *   | 16     | 8   | Y
+     *   | 24     | 8   | sizeof(new_file)
+     *   | 32     | X   | bzip2(control block)
+     *   | 32+X   | Y   | bzip2(diff block)
+     *   | 32+X+Y | ??? | bzip2(extra block)
+     *
+     * With control block a set of triples (x, y, z) meaning "add x bytes
+     * from old_file to x bytes from the diff block; copy y bytes from the
+     * extra block; seek forwards in old_file by z bytes".
+     *
+     * Or, in the format of Matthew Endsley's bsdiff:
+     *
+     *   | offset | len | data
+     * --+--------+-----+----------------------
+     *   | 0      | 16  | "ENDSLEY/BSDIFF43"
+     *   | 16     | 8   | sizeof(new_file)
+     *   | 24     | ??? | bzip2(blocks)
+     *
+     * Where each control triple is immediately followed by its x bytes of
+     * diff data and then its y bytes of extra data, all in the one stream.
+     */
+
+    // Read header
+    let mut header = [0u8; 32];
+    f.read_exact(&mut header[..24])
+        .map_err(Error::PatchReadError)?;
+
+    // Check for appropriate magic, and open the patch file in the right
+    // place(s) with libbzip2
+    let (patch_header, blocks) = if header[..16] == ENDSLEY_MAGIC[..] {
+        let newsize = offtin(&header[16..]);
+        if newsize < 0 {
+            return Err(Error::BadPatchSize);
+        }
+
+        // `f` is already at the start of the stream
+        (
+            PatchHeader {
+                format: PatchFormat::Endsley,
+                bzctrllen: None,
+                bzdatalen: None,
+                new_size: newsize as u64,
+            },
+            Blocks::Interleaved(BzReadDecoder::new(f)),
+        )
+    } else if header[..8] == BSDIFF40_MAGIC[..] {
+        f.read_exact(&mut header[24..])
+            .map_err(Error::PatchReadError)?;
+
+        // Read lengths from header
+        let bzctrllen = offtin(&header[8..]);
+        let bzdatalen = offtin(&header[16..]);
+        let newsize = offtin(&header[24..]);
+        if bzctrllen < 0 || bzdatalen < 0 || newsize < 0 {
+            return Err(Error::BadPatchSize);
+        }
+
+        // `f` is already at the start of the control block
+        let cpfbz2 = BzReadDecoder::new(f);
+        let dpf = Section {
+            src: patch,
+            pos: base + 32 + bzctrllen as u64,
+        };
+        let dpfbz2 = BzReadDecoder::new(dpf);
+        let epf = Section {
+            src: patch,
+            pos: base + 32 + bzctrllen as u64 + bzdatalen as u64,
+        };
+        let epfbz2 = BzReadDecoder::new(epf);
+
+        (
+            PatchHeader {
+                format: PatchFormat::Bsdiff40,
+                bzctrllen: Some(bzctrllen as u64),
+                bzdatalen: Some(bzdatalen as u64),
+                new_size: newsize as u64,
+            },
+            Blocks::Separate {
+                ctrl: cpfbz2,
+                diff: dpfbz2,
+                extra: epfbz2,
+            },
+        )
+    } else {
+        return Err(Error::BadPatchVersion);
+    };
+
+    Ok((patch_header, blocks))
+}
+
+/// Replaces the path-less errors that come from patching arbitrary readers
+/// and writers with the equivalent errors about the files involved.
+fn attach_paths(
+    e: Error,
+    patch_file_path: &Path,
+    old_file_path: Option<&Path>,
+    new_file_path: Option<&Path>,
+) -> Error {
+    match (e, old_file_path, new_file_path) {
+        (Error::PatchReadError(ioe), _, _) => {
+            Error::FileReadError(patch_file_path.to_path_buf(), ioe)
+        }
+        (Error::PatchDecodeError(ioe), _, _) => {
+            Error::DecodeError(patch_file_path.to_path_buf(), ioe)
+        }
+        (Error::OldDataReadError(ioe), Some(path), _) => {
+            Error::FileReadError(path.to_path_buf(), ioe)
+        }
+        (Error::NewDataWriteError(ioe), _, Some(path)) => {
+            Error::FileWriteError(path.to_path_buf(), ioe)
+        }
+        (e, _, _) => e,
+    }
+}
+
+/// Reads and throws away exactly `len` bytes from `r`.
+fn skip_exactly<R: Read>(r: &mut R, len: u64) -> io::Result<()> {
+    if io::copy(&mut r.take(len), &mut io::sink())? == len {
+        Ok(())
+    } else {
+        Err(io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+/// Whether or not there's nothing left to read from `r`.
+fn is_exhausted<R: Read>(r: &mut R) -> io::Result<bool> {
+    Ok(r.read(&mut [0u8; 1])? == 0)
 }
 
 fn offtin(buf: &[u8]) -> i64 {
@@ -249,3 +737,342 @@ fn offtin(buf: &[u8]) -> i64 {
 
     y
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::{write::BzEncoder, Compression};
+    use std::io::Cursor;
+
+    /// One control triple, along with its diff and extra data.
+    struct Triple {
+        diff: Vec<u8>,
+        extra: Vec<u8>,
+        seek: i64,
+    }
+
+    /// The inverse of `offtin`.
+    fn offtout(x: i64) -> [u8; 8] {
+        let mut buf = x.unsigned_abs().to_le_bytes();
+        if x < 0 {
+            buf[7] |= 0x80;
+        }
+
+        buf
+    }
+
+    fn bz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(data).unwrap();
+
+        encoder.finish().unwrap()
+    }
+
+    fn new_size(triples: &[Triple]) -> i64 {
+        triples
+            .iter()
+            .map(|t| (t.diff.len() + t.extra.len()) as i64)
+            .sum()
+    }
+
+    fn ctrl_bytes(t: &Triple) -> Vec<u8> {
+        [
+            offtout(t.diff.len() as i64),
+            offtout(t.extra.len() as i64),
+            offtout(t.seek),
+        ]
+        .concat()
+    }
+
+    /// A BSDIFF40 patch with the given blocks, already compressed.
+    fn bsdiff40_from_blocks(
+        ctrl: &[u8],
+        diff: &[u8],
+        extra: &[u8],
+        new_size: i64,
+    ) -> Vec<u8> {
+        let mut patch = BSDIFF40_MAGIC.to_vec();
+        patch.extend_from_slice(&offtout(ctrl.len() as i64));
+        patch.extend_from_slice(&offtout(diff.len() as i64));
+        patch.extend_from_slice(&offtout(new_size));
+        patch.extend_from_slice(ctrl);
+        patch.extend_from_slice(diff);
+        patch.extend_from_slice(extra);
+
+        patch
+    }
+
+    fn bsdiff40(triples: &[Triple]) -> Vec<u8> {
+        let ctrl: Vec<u8> = triples.iter().flat_map(ctrl_bytes).collect();
+        let diff: Vec<u8> =
+            triples.iter().flat_map(|t| t.diff.clone()).collect();
+        let extra: Vec<u8> =
+            triples.iter().flat_map(|t| t.extra.clone()).collect();
+
+        bsdiff40_from_blocks(
+            &bz(&ctrl),
+            &bz(&diff),
+            &bz(&extra),
+            new_size(triples),
+        )
+    }
+
+    /// An ENDSLEY/BSDIFF43 patch, with `trailing` tacked onto the end of its
+    /// (uncompressed) stream.
+    fn endsley(triples: &[Triple], trailing: &[u8]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for t in triples {
+            stream.extend(ctrl_bytes(t));
+            stream.extend_from_slice(&t.diff);
+            stream.extend_from_slice(&t.extra);
+        }
+        stream.extend_from_slice(trailing);
+
+        let mut patch = ENDSLEY_MAGIC.to_vec();
+        patch.extend_from_slice(&offtout(new_size(triples)));
+        patch.extend(bz(&stream));
+
+        patch
+    }
+
+    /// Applies `triples` to `old` the way that the original bsdiff does,
+    /// entirely in memory.
+    fn reference_apply(old: &[u8], triples: &[Triple]) -> Vec<u8> {
+        let mut new = Vec::new();
+        let mut oldpos = 0i64;
+        for t in triples {
+            for (i, d) in t.diff.iter().enumerate() {
+                let pos = oldpos + i as i64;
+                if pos >= 0 && pos < old.len() as i64 {
+                    new.push(d.wrapping_add(old[pos as usize]));
+                } else {
+                    new.push(*d);
+                }
+            }
+            new.extend_from_slice(&t.extra);
+            oldpos += t.diff.len() as i64 + t.seek;
+        }
+
+        new
+    }
+
+    /// Whether any diff data is added to old data from before the start of
+    /// the old file, which `inspect` rejects.
+    fn seeks_before_start(triples: &[Triple]) -> bool {
+        let mut oldpos = 0i64;
+        for t in triples {
+            if !t.diff.is_empty() && oldpos < 0 {
+                return true;
+            }
+            oldpos += t.diff.len() as i64 + t.seek;
+        }
+
+        false
+    }
+
+    /// xorshift64, so that the "random" patches are the same every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn bytes(&mut self, len: u64) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    /// Random old data and a random patch against it, with seeks that may
+    /// well land outside of the old data.
+    fn random_case(rng: &mut Rng, max_len: u64) -> (Vec<u8>, Vec<Triple>) {
+        let old_len = rng.below(max_len);
+        let old = rng.bytes(old_len);
+        let triples = (0..1 + rng.below(8))
+            .map(|_| {
+                let diff_len = rng.below(max_len);
+                let extra_len = rng.below(max_len / 4);
+                let span = 2 * (old_len + 100);
+
+                Triple {
+                    diff: rng.bytes(diff_len),
+                    extra: rng.bytes(extra_len),
+                    seek: rng.below(span) as i64 - span as i64 / 2,
+                }
+            })
+            .collect();
+
+        (old, triples)
+    }
+
+    fn apply_to_vec(patch: &[u8], old: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut new = Vec::new();
+        apply(Cursor::new(patch), Cursor::new(old), &mut new)?;
+
+        Ok(new)
+    }
+
+    #[test]
+    fn apply_matches_reference() {
+        let mut rng = Rng(0x5eed_5eed_5eed_5eed);
+        for i in 0..40 {
+            // A few cases big enough to span several chunks
+            let max_len = if i % 10 == 0 {
+                3 * CHUNK_SIZE as u64
+            } else {
+                2000
+            };
+            let (old, triples) = random_case(&mut rng, max_len);
+            let expected = reference_apply(&old, &triples);
+
+            for patch in &[bsdiff40(&triples), endsley(&triples, &[])] {
+                assert_eq!(apply_to_vec(patch, &old).unwrap(), expected);
+
+                match inspect(Cursor::new(patch)) {
+                    Ok(info) => {
+                        assert!(!seeks_before_start(&triples));
+                        assert_eq!(
+                            info.header.new_size,
+                            expected.len() as u64
+                        );
+                        assert_eq!(
+                            info.diff_bytes + info.extra_bytes,
+                            expected.len() as u64,
+                        );
+                    }
+                    Err(Error::PatchInconsistent(_)) => {
+                        assert!(seeks_before_start(&triples));
+                    }
+                    Err(e) => panic!("Unexpected error: {}", e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn patches_are_read_from_the_current_position() {
+        let old = b"The quick brown fox jumps over the lazy dog".to_vec();
+        let triples = vec![
+            Triple {
+                diff: vec![0; 10],
+                extra: b"red".to_vec(),
+                seek: 5,
+            },
+            Triple {
+                diff: vec![0; 28],
+                extra: b" and cat".to_vec(),
+                seek: 0,
+            },
+        ];
+        let expected = b"The quick red fox jumps over the lazy dog and cat";
+        assert_eq!(reference_apply(&old, &triples), &expected[..]);
+
+        for patch in &[bsdiff40(&triples), endsley(&triples, &[])] {
+            let mut prefixed = b"junk before the patch".to_vec();
+            let base = prefixed.len() as u64;
+            prefixed.extend_from_slice(patch);
+
+            let mut cursor = Cursor::new(&prefixed);
+            cursor.set_position(base);
+            let mut new = Vec::new();
+            apply(cursor, Cursor::new(&old), &mut new).unwrap();
+            assert_eq!(new, &expected[..]);
+
+            let mut cursor = Cursor::new(&prefixed);
+            cursor.set_position(base);
+            let info = inspect(cursor).unwrap();
+            assert_eq!(info.ctrl_triples, 2);
+            assert_eq!(info.diff_bytes, 38);
+            assert_eq!(info.extra_bytes, 11);
+            assert_eq!(info.min_old_size, old.len() as u64);
+        }
+    }
+
+    #[test]
+    fn inspect_rejects_trailing_data() {
+        let triples = vec![Triple {
+            diff: vec![1, 2, 3],
+            extra: vec![4, 5],
+            seek: 0,
+        }];
+
+        // An extra block that's longer than the control block says
+        let ctrl: Vec<u8> = triples.iter().flat_map(ctrl_bytes).collect();
+        let patch = bsdiff40_from_blocks(
+            &bz(&ctrl),
+            &bz(&triples[0].diff),
+            &bz(&[4, 5, 6]),
+            new_size(&triples),
+        );
+        assert!(matches!(
+            inspect(Cursor::new(&patch)),
+            Err(Error::PatchInconsistent(_)),
+        ));
+
+        let patch = endsley(&triples, &[0; 24]);
+        assert!(matches!(
+            inspect(Cursor::new(&patch)),
+            Err(Error::PatchInconsistent(_)),
+        ));
+    }
+
+    #[test]
+    fn inspect_rejects_wrong_bzctrllen() {
+        let triples = vec![Triple {
+            diff: vec![1, 2, 3],
+            extra: vec![4, 5],
+            seek: 0,
+        }];
+        let patch = bsdiff40(&triples);
+        assert!(inspect(Cursor::new(&patch)).is_ok());
+
+        // Junk between the control and diff blocks, which `bzctrllen` covers
+        // but which the control block doesn't actually take up
+        let bzctrllen = offtin(&patch[8..16]);
+        let ctrl_end = 32 + bzctrllen as usize;
+        let mut padded = patch[..ctrl_end].to_vec();
+        padded[8..16].copy_from_slice(&offtout(bzctrllen + 4));
+        padded.extend_from_slice(&[0; 4]);
+        padded.extend_from_slice(&patch[ctrl_end..]);
+        assert!(matches!(
+            inspect(Cursor::new(&padded)),
+            Err(Error::PatchInconsistent(_)),
+        ));
+
+        // A `bzctrllen` that runs past the end of the patch
+        let mut overlong = patch.clone();
+        overlong[8..16].copy_from_slice(&offtout(patch.len() as i64));
+        assert!(matches!(
+            inspect(Cursor::new(&overlong)),
+            Err(Error::PatchInconsistent(_)),
+        ));
+    }
+
+    #[test]
+    fn negative_ctrl_is_rejected() {
+        let ctrl = [offtout(-1), offtout(0), offtout(0)].concat();
+        let bsdiff40 = bsdiff40_from_blocks(&bz(&ctrl), &bz(&[]), &bz(&[]), 1);
+        let mut endsley = ENDSLEY_MAGIC.to_vec();
+        endsley.extend_from_slice(&offtout(1));
+        endsley.extend(bz(&ctrl));
+
+        for patch in &[bsdiff40, endsley] {
+            assert!(matches!(
+                inspect(Cursor::new(patch)),
+                Err(Error::PatchSanityCheckFail(0)),
+            ));
+            assert!(matches!(
+                apply_to_vec(patch, b"old"),
+                Err(Error::PatchSanityCheckFail(0)),
+            ));
+        }
+    }
+}
@@ -0,0 +1,716 @@
+use crate::{
+    config::{commit_config, Config},
+    credential,
+    error::Error,
+    login::{self, LoginHandler, LoginOutcome},
+    retry::RetryPolicy,
+    totp,
+    vault::{self, Vault},
+};
+use reqwest::blocking as rb;
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+    vec,
+};
+
+/// How many times the master password can be mistyped before giving up.
+const MASTER_PASSWORD_ATTEMPTS: usize = 3;
+
+/// Where passwords, two-factor tokens, and the vault's master password come
+/// from, other than saved passwords and prompting, along with the vault once
+/// it has been unlocked. This lasts for the whole session.
+#[derive(Default)]
+pub struct Secrets {
+    pub vault: Option<Vault>,
+    pub passwords: Option<SecretSource>,
+    pub two_factor_tokens: Option<SecretSource>,
+    pub master_passwords: Option<SecretSource>,
+}
+
+/// Somewhere that passwords or two-factor tokens are read from instead of
+/// prompting for them, so that logging in doesn't need a TTY.
+pub enum SecretSource {
+    /// The same secret every time.
+    Fixed(String),
+    /// One secret per line, read from stdin as each one is needed.
+    Stdin,
+    /// One secret per line, as read from a file up front.
+    Lines(vec::IntoIter<String>),
+}
+
+impl SecretSource {
+    /// The value of the environment variable `var`, for every login.
+    pub fn env(var: &str) -> Result<Self, Error> {
+        env::var(var)
+            .map(Self::Fixed)
+            .map_err(|_| Error::MissingEnvVar(var.to_owned()))
+    }
+
+    /// The lines of the file at `path`, in order. The whole file is read
+    /// right away, so it can just as well be a pipe.
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = fs::read_to_string(&path).map_err(|ioe| {
+            Error::FileReadError(path.as_ref().to_path_buf(), ioe)
+        })?;
+
+        Ok(Self::Lines(
+            contents
+                .lines()
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+                .into_iter(),
+        ))
+    }
+
+    /// The lines of the inherited file descriptor `fd`, in order.
+    #[cfg(unix)]
+    pub fn fd(fd: u32) -> Result<Self, Error> {
+        Self::file(format!("/dev/fd/{}", fd))
+    }
+
+    /// The next secret. `what` is what kind of secret it is, for the error
+    /// if there are none left.
+    fn next(&mut self, what: &'static str) -> Result<String, Error> {
+        match self {
+            Self::Fixed(secret) => Ok(secret.clone()),
+            Self::Stdin => {
+                let mut line = String::with_capacity(0x20);
+                if io::stdin()
+                    .read_line(&mut line)
+                    .map_err(Error::StdinError)?
+                    == 0
+                {
+                    return Err(Error::SecretsExhausted(what));
+                }
+                line.truncate(line.trim_end_matches(&['\r', '\n'][..]).len());
+
+                Ok(line)
+            }
+            Self::Lines(lines) => {
+                lines.next().ok_or(Error::SecretsExhausted(what))
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn login<'a, P: AsRef<Path>, A: Iterator<Item = &'a str>>(
+    config: &mut Config,
+    config_path: P,
+    client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    quiet: bool,
+    argv: A,
+    secrets: &mut Secrets,
+    children: &mut Vec<(String, process::Child, Instant)>,
+) -> Result<(), Error> {
+    let (mut usernames, mut no_save) = (Vec::new(), false);
+    for arg in argv {
+        match arg {
+            "-n" | "--no-save" => no_save = true,
+            _ => usernames.push(arg),
+        }
+    }
+
+    let mut username_buf = String::new();
+
+    if !usernames.is_empty() {
+        for username in usernames {
+            let (password, password_origin) = password_for(
+                config,
+                config_path.as_ref(),
+                secrets,
+                quiet,
+                username,
+            )?;
+
+            if let Some(c) = handle_name_and_pw(
+                config,
+                config_path.as_ref(),
+                client,
+                retry_policy,
+                quiet,
+                no_save,
+                secrets,
+                username.to_owned(),
+                password,
+                password_origin,
+            )? {
+                children.push(c);
+            }
+        }
+    } else {
+        print!("Username: ");
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        username_buf.reserve(0x10);
+        io::stdin()
+            .read_line(&mut username_buf)
+            .map_err(Error::StdinError)?;
+        username_buf.truncate(username_buf.trim_end().len());
+
+        let (password, password_origin) = password_for(
+            config,
+            config_path.as_ref(),
+            secrets,
+            quiet,
+            &username_buf,
+        )?;
+
+        if let Some(c) = handle_name_and_pw(
+            config,
+            config_path.as_ref(),
+            client,
+            retry_policy,
+            quiet,
+            no_save,
+            secrets,
+            username_buf,
+            password,
+            password_origin,
+        )? {
+            children.push(c);
+        }
+    }
+
+    if !quiet && config.has_plaintext_passwords() {
+        println!(
+            "Some passwords are still saved in plain text in the config \
+             file. Type \"vault migrate\" to move them into the encrypted \
+             vault.",
+        );
+    }
+
+    Ok(())
+}
+
+/// Handles the `vault` command.
+pub fn vault_command<'a, P: AsRef<Path>, A: Iterator<Item = &'a str>>(
+    config: &mut Config,
+    config_path: P,
+    quiet: bool,
+    secrets: &mut Secrets,
+    mut argv: A,
+) -> Result<(), Error> {
+    match (argv.next(), argv.next()) {
+        (Some("migrate"), None) => {
+            let unlocked = unlock_vault(
+                config,
+                config_path.as_ref(),
+                &mut secrets.vault,
+                secrets.master_passwords.as_mut(),
+                quiet,
+            )?;
+            let migrated =
+                vault::migrate(config, config_path.as_ref(), unlocked)?;
+            if migrated == 0 {
+                println!("There are no plain text passwords to migrate.");
+            } else {
+                println!(
+                    "Moved {} password(s) from the config file into the \
+                     vault.",
+                    migrated,
+                );
+            }
+        }
+        (Some("lock"), None) => {
+            secrets.vault = None;
+            if !quiet {
+                println!("Vault locked.");
+            }
+        }
+        _ => println!("Usage: vault migrate | vault lock"),
+    }
+
+    Ok(())
+}
+
+/// Handles the `totp` command.
+pub fn totp_command<'a, P: AsRef<Path>, A: Iterator<Item = &'a str>>(
+    config: &mut Config,
+    config_path: P,
+    quiet: bool,
+    secrets: &mut Secrets,
+    mut argv: A,
+) -> Result<(), Error> {
+    let (action, username) = match (argv.next(), argv.next(), argv.next()) {
+        (Some(action @ "set"), Some(username), None)
+        | (Some(action @ "remove"), Some(username), None) => {
+            (action, username)
+        }
+        _ => {
+            println!("Usage: totp set <username> | totp remove <username>");
+
+            return Ok(());
+        }
+    };
+    if config.credential_helper.is_some() {
+        println!(
+            "TOTP secrets are got from the credential helper, so that's where \
+             they have to be set.",
+        );
+
+        return Ok(());
+    }
+
+    if action == "set" {
+        print!(
+            "TOTP secret for {} (as shown when setting up an authenticator \
+             app): ",
+            username,
+        );
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        let encoded = rpassword::read_password_from_tty(None)
+            .map_err(Error::PasswordReadError)?;
+        let secret = match totp::decode_secret(&encoded) {
+            Ok(secret) => secret,
+            Err(e) => {
+                println!("{}", e);
+
+                return Ok(());
+            }
+        };
+
+        let unlocked = unlock_vault(
+            config,
+            config_path.as_ref(),
+            &mut secrets.vault,
+            secrets.master_passwords.as_mut(),
+            quiet,
+        )?;
+        unlocked.insert_totp_secret(username.to_owned(), encoded);
+        unlocked.save()?;
+        if config.totp_accounts.insert(username.to_owned()) {
+            commit_config(config, config_path)?;
+        }
+        println!(
+            "TOTP secret saved. The current token is {}, which should match \
+             the one that your authenticator app shows.",
+            totp::token(&secret),
+        );
+    } else {
+        let unlocked = unlock_vault(
+            config,
+            config_path.as_ref(),
+            &mut secrets.vault,
+            secrets.master_passwords.as_mut(),
+            quiet,
+        )?;
+        let removed = unlocked.remove_totp_secret(username).is_some();
+        if removed {
+            unlocked.save()?;
+        }
+        if config.totp_accounts.remove(username) {
+            commit_config(config, config_path)?;
+        }
+        if removed {
+            if !quiet {
+                println!("TOTP secret removed.");
+            }
+        } else {
+            println!("There is no TOTP secret saved for {}.", username);
+        }
+    }
+
+    Ok(())
+}
+
+/// Where a password came from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PasswordOrigin {
+    /// A source of passwords, which already keeps them elsewhere.
+    Source,
+    /// The credential helper.
+    Helper,
+    /// The vault, or plain text in the config.
+    Saved,
+    /// Typed in at the prompt.
+    Prompt,
+}
+
+impl PasswordOrigin {
+    /// Whether a password from here is worth saving once it works.
+    fn should_save(self) -> bool {
+        self != Self::Source
+    }
+}
+
+/// The password for `username`: from `secrets` if they have a source of
+/// passwords, and otherwise the saved one, or else prompted for. Where the
+/// password came from is also returned.
+fn password_for<P: AsRef<Path>>(
+    config: &Config,
+    config_path: P,
+    secrets: &mut Secrets,
+    quiet: bool,
+    username: &str,
+) -> Result<(String, PasswordOrigin), Error> {
+    if let Some(passwords) = &mut secrets.passwords {
+        return passwords
+            .next("password")
+            .map(|p| (p, PasswordOrigin::Source));
+    }
+
+    if let Some(password) = saved_password(
+        config,
+        config_path,
+        &mut secrets.vault,
+        secrets.master_passwords.as_mut(),
+        quiet,
+        username,
+    )? {
+        let origin = if config.credential_helper.is_some() {
+            PasswordOrigin::Helper
+        } else {
+            PasswordOrigin::Saved
+        };
+
+        return Ok((password, origin));
+    }
+
+    print!("Password for {}: ", username);
+    io::stdout().flush().map_err(Error::StdoutError)?;
+
+    rpassword::read_password_from_tty(None)
+        .map(|p| (p, PasswordOrigin::Prompt))
+        .map_err(Error::PasswordReadError)
+}
+
+/// The password saved for `username`, if any: from the credential helper if
+/// there is one, and otherwise first from the vault (unlocking it if need
+/// be), and then from plain text in the config.
+fn saved_password<P: AsRef<Path>>(
+    config: &Config,
+    config_path: P,
+    vault: &mut Option<Vault>,
+    master_passwords: Option<&mut SecretSource>,
+    quiet: bool,
+    username: &str,
+) -> Result<Option<String>, Error> {
+    if let Some(helper) = &config.credential_helper {
+        let password = credential::get(helper, username)?;
+        if password.is_some() && !quiet {
+            println!("Using password from credential helper...");
+        }
+
+        return Ok(password);
+    }
+
+    let in_vault = if config.accounts.contains_key(username)
+        && (vault.is_some()
+            || config.vault_path(config_path.as_ref()).exists())
+    {
+        unlock_vault(config, config_path, vault, master_passwords, quiet)?
+            .get(username)
+            .map(str::to_owned)
+    } else {
+        None
+    };
+
+    let password = in_vault
+        .or_else(|| config.plaintext_password(username).map(str::to_owned));
+    if password.is_some() && !quiet {
+        println!("Using saved password...");
+    }
+
+    Ok(password)
+}
+
+/// The (decoded) TOTP secret for `username`, if it has one: from the
+/// credential helper if there is one, and otherwise from the vault. The vault
+/// is only unlocked for this if the config says that `username` has a secret
+/// in it, so that two-factor logins don't ask for a master password for
+/// nothing.
+fn totp_secret<P: AsRef<Path>>(
+    config: &Config,
+    config_path: P,
+    vault: &mut Option<Vault>,
+    master_passwords: Option<&mut SecretSource>,
+    quiet: bool,
+    username: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    let encoded = if let Some(helper) = &config.credential_helper {
+        credential::get_totp_secret(helper, username)?
+    } else if vault.is_some()
+        || (config.totp_accounts.contains(username)
+            && config.vault_path(config_path.as_ref()).exists())
+    {
+        unlock_vault(config, config_path, vault, master_passwords, quiet)?
+            .totp_secret(username)
+            .map(str::to_owned)
+    } else {
+        None
+    };
+
+    encoded.map(|e| totp::decode_secret(&e)).transpose()
+}
+
+/// The password vault, unlocked. The master password is only asked for (or
+/// taken from `master_passwords`) the first time in a session, and if there is
+/// no vault yet, a new one is created.
+fn unlock_vault<'v, P: AsRef<Path>>(
+    config: &Config,
+    config_path: P,
+    vault: &'v mut Option<Vault>,
+    master_passwords: Option<&mut SecretSource>,
+    quiet: bool,
+) -> Result<&'v mut Vault, Error> {
+    match vault {
+        Some(unlocked) => Ok(unlocked),
+        None => {
+            let vault_path = config.vault_path(config_path);
+            let unlocked = match master_passwords {
+                // Nobody is there to retype it, so there's only one attempt.
+                Some(master_passwords) => {
+                    let master_password =
+                        master_passwords.next("master password")?;
+                    if vault_path.exists() {
+                        Vault::unlock(&vault_path, &master_password)?
+                    } else if master_password.is_empty() {
+                        return Err(Error::EmptyMasterPassword);
+                    } else {
+                        if !quiet {
+                            println!(
+                                "Creating a new password vault at {}...",
+                                vault_path.display(),
+                            );
+                        }
+
+                        Vault::create(vault_path, &master_password)?
+                    }
+                }
+                None if vault_path.exists() => open_vault(vault_path)?,
+                None => create_vault(vault_path, quiet)?,
+            };
+
+            Ok(vault.insert(unlocked))
+        }
+    }
+}
+
+fn open_vault(vault_path: PathBuf) -> Result<Vault, Error> {
+    let mut attempts = 1;
+    loop {
+        print!("Master password: ");
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        let master_password = rpassword::read_password_from_tty(None)
+            .map_err(Error::PasswordReadError)?;
+
+        match Vault::unlock(&vault_path, &master_password) {
+            Err(Error::VaultDecryptError(_))
+                if attempts < MASTER_PASSWORD_ATTEMPTS =>
+            {
+                println!("Incorrect master password.");
+                attempts += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+fn create_vault(vault_path: PathBuf, quiet: bool) -> Result<Vault, Error> {
+    if !quiet {
+        println!(
+            "Creating a new password vault at {}...",
+            vault_path.display(),
+        );
+    }
+
+    loop {
+        print!("New master password: ");
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        let master_password = rpassword::read_password_from_tty(None)
+            .map_err(Error::PasswordReadError)?;
+        if master_password.is_empty() {
+            println!("The master password can't be empty.");
+
+            continue;
+        }
+
+        print!("Confirm master password: ");
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        let confirmation = rpassword::read_password_from_tty(None)
+            .map_err(Error::PasswordReadError)?;
+        if confirmation == master_password {
+            return Vault::create(vault_path, &master_password);
+        }
+
+        println!("The master passwords didn't match.");
+    }
+}
+
+/// Does all of the prompting that logging in might need.
+struct Prompt<'s> {
+    quiet: bool,
+    two_factor_asked: bool,
+    two_factor_tokens: Option<&'s mut SecretSource>,
+    /// For looking up the TOTP secret that two-factor tokens can be generated
+    /// from, if there is one.
+    config: &'s Config,
+    config_path: &'s Path,
+    vault: &'s mut Option<Vault>,
+    master_passwords: Option<&'s mut SecretSource>,
+    username: &'s str,
+}
+
+impl LoginHandler for Prompt<'_> {
+    fn two_factor_token(
+        &mut self,
+        banner: &str,
+    ) -> Result<Option<String>, Error> {
+        self.two_factor_asked = true;
+        if let Some(tokens) = &mut self.two_factor_tokens {
+            return tokens.next("two-factor token").map(Some);
+        }
+
+        if let Some(secret) = totp_secret(
+            self.config,
+            self.config_path,
+            self.vault,
+            self.master_passwords.as_deref_mut(),
+            self.quiet,
+            self.username,
+        )? {
+            if !self.quiet {
+                println!("Using generated two-factor token...");
+            }
+
+            return Ok(Some(totp::token(&secret)));
+        }
+
+        print!("{}\n(...or type \"cancel\" to cancel):\n> ", banner);
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        let mut app_token = String::with_capacity(0x10);
+        io::stdin()
+            .read_line(&mut app_token)
+            .map_err(Error::StdinError)?;
+        app_token.truncate(app_token.trim_end().len());
+
+        if app_token == "cancel" {
+            Ok(None)
+        } else {
+            Ok(Some(app_token))
+        }
+    }
+
+    fn queued(
+        &mut self,
+        eta: f64,
+        position: Option<u64>,
+    ) -> Result<(), Error> {
+        if !self.quiet {
+            println!(
+                "Waiting in queue... ETA: {}, position in line: {}",
+                eta,
+                position.ok_or(Error::BadLoginResponse(
+                    "Expected \"position\" key with a String or unsigned \
+                     Number value",
+                ))?,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_name_and_pw<P: AsRef<Path>>(
+    config: &mut Config,
+    config_path: P,
+    client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    quiet: bool,
+    no_save: bool,
+    secrets: &mut Secrets,
+    username: String,
+    password: String,
+    password_origin: PasswordOrigin,
+) -> Result<Option<(String, process::Child, Instant)>, Error> {
+    let mut prompt = Prompt {
+        quiet,
+        two_factor_asked: false,
+        two_factor_tokens: secrets.two_factor_tokens.as_mut(),
+        config,
+        config_path: config_path.as_ref(),
+        vault: &mut secrets.vault,
+        master_passwords: secrets.master_passwords.as_mut(),
+        username: &username,
+    };
+    let session = match login::negotiate(
+        client,
+        retry_policy,
+        &config.login_api_uri,
+        &username,
+        &password,
+        &mut prompt,
+    )? {
+        LoginOutcome::Success(session) => session,
+        LoginOutcome::Failed(banner) => {
+            println!("Login failed: {}", banner);
+
+            // Failing after two-factor authentication means that it was the
+            // token, not the password, that was wrong. Only a password that
+            // the helper gave us is known to be what the helper has stored.
+            if let (Some(helper), PasswordOrigin::Helper, false) = (
+                &config.credential_helper,
+                password_origin,
+                prompt.two_factor_asked,
+            ) {
+                if let Err(e) = credential::erase(helper, &username) {
+                    eprintln!("{}", e);
+                }
+            }
+
+            return Ok(None);
+        }
+        LoginOutcome::Cancelled => return Ok(None),
+    };
+
+    if !quiet {
+        println!("Authentication success!");
+    }
+
+    if !no_save {
+        if password_origin.should_save() {
+            if let Some(helper) = &config.credential_helper {
+                if let Err(e) = credential::store(helper, &username, &password)
+                {
+                    eprintln!("{}", e);
+                }
+            } else if config.store_passwords {
+                let unlocked = unlock_vault(
+                    config,
+                    config_path.as_ref(),
+                    &mut secrets.vault,
+                    secrets.master_passwords.as_mut(),
+                    quiet,
+                )?;
+                if unlocked.get(&username) != Some(password.as_str()) {
+                    unlocked.insert(username.clone(), password);
+                    unlocked.save()?;
+                }
+            }
+        }
+
+        let old_acc = config.add_account(username.clone());
+        commit_config(config, config_path)?;
+        if !quiet && old_acc.is_none() {
+            println!("New account saved in config!");
+        }
+    }
+
+    if !quiet {
+        println!("Launching the game...");
+    }
+
+    let ret = login::launch(config, &session)
+        .map(|c| Some((username, c, Instant::now())));
+    if !quiet && ret.is_ok() {
+        println!("Game launched successfully!");
+    }
+
+    ret
+}
@@ -0,0 +1,179 @@
+use crate::error::Error;
+use reqwest::{blocking as rb, header};
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    num::NonZeroUsize,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Servers are trusted to know how long they need, but not unboundedly so.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Shared policy for anything that talks to the network: how many times to
+/// try, and how long to back off between tries.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_tries: NonZeroUsize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    retries: Cell<u64>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_tries: NonZeroUsize) -> Self {
+        Self {
+            max_tries,
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+            retries: Cell::new(0),
+        }
+    }
+
+    /// Total number of times that something has been retried (including
+    /// moving on to another mirror) under this policy so far.
+    pub fn retries(&self) -> u64 {
+        self.retries.get()
+    }
+
+    /// Calls `attempt` (with the 1-indexed attempt number) until it succeeds,
+    /// fails with an error that isn't transient, or runs out of tries. Each
+    /// failure is reported to stderr, prefixed by `indent`.
+    pub(crate) fn run<T, F: FnMut(usize) -> Result<T, Error>>(
+        &self,
+        indent: &str,
+        mut attempt: F,
+    ) -> Result<T, Error> {
+        self.run_with_failover(indent, 1, |i, _| attempt(i))
+            .map(|(t, _)| t)
+    }
+
+    /// Like `run`, but spread over `mirror_count` interchangeable mirrors,
+    /// which `attempt` is called with the index of. Mirrors are tried in
+    /// order, and any failure moves straight on to the next mirror; a mirror
+    /// that fails with an error that isn't transient is dropped altogether.
+    /// Backoff only happens once every remaining mirror has failed, so each
+    /// mirror gets at most `max_tries` attempts.
+    ///
+    /// On success, the index of the mirror that worked is returned alongside
+    /// the result. `mirror_count` must not be zero.
+    pub(crate) fn run_with_failover<T, F>(
+        &self,
+        indent: &str,
+        mirror_count: usize,
+        mut attempt: F,
+    ) -> Result<(T, usize), Error>
+    where
+        F: FnMut(usize, usize) -> Result<T, Error>,
+    {
+        let max_tries = self.max_tries.get();
+        let mut live = vec![true; mirror_count];
+        let mut last_err = None;
+
+        for i in 1..=max_tries {
+            let mut retry_after = None;
+
+            for m in 0..mirror_count {
+                if !live[m] {
+                    continue;
+                }
+
+                let e = match attempt(i, m) {
+                    Ok(t) => return Ok((t, m)),
+                    Err(e) => e,
+                };
+
+                if !e.is_transient() {
+                    live[m] = false;
+                }
+                retry_after = retry_after.max(e.retry_after());
+
+                if live[m + 1..].iter().any(|&l| l) {
+                    eprintln!("{}{}, trying the next mirror...", indent, e);
+                    self.retries.set(self.retries.get() + 1);
+                } else if i < max_tries && live.iter().any(|&l| l) {
+                    let delay = self.delay_for(i, retry_after);
+                    eprintln!(
+                        "{}{}, retrying in {}.{:01}s...",
+                        indent,
+                        e,
+                        delay.as_secs(),
+                        delay.subsec_millis() / 100,
+                    );
+                    thread::sleep(delay);
+                    self.retries.set(self.retries.get() + 1);
+                } else if e.is_transient() {
+                    eprintln!("{}{}, no more attempts remaining!", indent, e);
+                }
+
+                last_err = Some(e);
+            }
+
+            if !live.iter().any(|&l| l) {
+                break;
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| unreachable!()))
+    }
+
+    /// Exponential backoff with "equal jitter": the delay is somewhere
+    /// between half of, and all of, the exponentially growing ceiling. If the
+    /// server asked for a specific delay, we wait at least that long.
+    fn delay_for(
+        &self,
+        attempt: usize,
+        retry_after: Option<Duration>,
+    ) -> Duration {
+        let shift = (attempt.saturating_sub(1)).min(16) as u32;
+        let ceiling = self
+            .base_delay
+            .checked_mul(1 << shift)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let half_ceiling_ms = ceiling.as_millis() as u64 / 2;
+        let jittered = Duration::from_millis(
+            half_ceiling_ms + jitter() % (half_ceiling_ms + 1),
+        );
+
+        if let Some(ra) = retry_after {
+            jittered.max(ra.min(MAX_RETRY_AFTER))
+        } else {
+            jittered
+        }
+    }
+}
+
+/// Parses the `Retry-After` header of a response, if any. Both the
+/// delay-seconds and HTTP-date forms are understood.
+pub(crate) fn retry_after(resp: &rb::Response) -> Option<Duration> {
+    let value = resp.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse() {
+        Some(Duration::from_secs(secs))
+    } else {
+        httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok()
+    }
+}
+
+/// A cheap source of randomness that doesn't require pulling in a whole RNG
+/// crate: `RandomState` is randomly keyed by the standard library.
+fn jitter() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+
+    hasher.finish()
+}
@@ -0,0 +1,160 @@
+use crate::{
+    config::{self, Config},
+    error::Error,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Finds, reads, and (if it doesn't exist yet) interactively creates the
+/// config file. Returns the config along with its path, which is empty if
+/// `no_config`.
+pub fn get_config(
+    no_config: bool,
+    config_path: Option<&str>,
+    install_path: Option<&str>,
+    cache_path: Option<&str>,
+    quiet: bool,
+) -> Result<(Config, PathBuf), Error> {
+    let inject_arg_values = |c| {
+        let c = if let Some(ip) = install_path {
+            Config {
+                install_dir: PathBuf::from(ip),
+                ..c
+            }
+        } else {
+            c
+        };
+
+        if let Some(cp) = cache_path {
+            Config {
+                cache_dir: PathBuf::from(cp),
+                ..c
+            }
+        } else {
+            c
+        }
+    };
+
+    if !no_config {
+        let config_path = if let Some(s) = config_path {
+            PathBuf::from(s)
+        } else {
+            config::default_config_path()?
+        };
+
+        if !quiet {
+            println!("Using {} as the config path...", config_path.display());
+        }
+
+        match File::open(&config_path) {
+            Ok(f) => config::read_config(f)
+                .map(|c| (inject_arg_values(c), config_path)),
+            Err(ioe) => match ioe.kind() {
+                io::ErrorKind::NotFound => {
+                    let config_dir =
+                        config_path.parent().ok_or_else(|| {
+                            Error::BadConfigPath(config_path.clone())
+                        })?;
+                    fs::create_dir_all(config_dir).map_err(|ioe| {
+                        Error::MkdirError(config_dir.to_path_buf(), ioe)
+                    })?;
+
+                    let new_config = prompt_for_config_values(&config_path)?;
+                    config::commit_config(&new_config, &config_path)?;
+
+                    Ok((inject_arg_values(new_config), config_path))
+                }
+                io::ErrorKind::PermissionDenied => {
+                    Err(Error::PermissionDenied(
+                        format!("opening {:?}", config_path),
+                        ioe,
+                    ))
+                }
+                _ => Err(Error::UnknownIoError(
+                    format!("opening {:?}", config_path),
+                    ioe,
+                )),
+            },
+        }
+    } else {
+        if !quiet {
+            println!("Not using any config file...");
+        }
+
+        Ok((
+            Config::new(
+                install_path.ok_or_else(|| {
+                    Error::MissingCommandLineArg("--install-dir")
+                })?,
+                cache_path.ok_or_else(|| {
+                    Error::MissingCommandLineArg("--cache-dir")
+                })?,
+            ),
+            PathBuf::new(),
+        ))
+    }
+}
+
+fn prompt_for_config_values<P: AsRef<Path>>(
+    config_path: P,
+) -> Result<Config, Error> {
+    print!(
+        "No configuration file was found at {}\nAnswer a few prompts, and a \
+         new config file will be created there.\n\nFully qualified path to \
+         desired TTR installation directory\n(will be created if it doesn't \
+         already exist):\n> ",
+        config_path.as_ref().display(),
+    );
+    io::stdout().flush().map_err(Error::StdoutError)?;
+    let mut install_dir = String::with_capacity(0x30);
+    io::stdin()
+        .read_line(&mut install_dir)
+        .map_err(Error::StdinError)?;
+
+    print!(
+        "\nDo you want passwords for your accounts to be saved? [yes/no]\nThe \
+         passwords will be stored in an encrypted vault next to the config \
+         file, which is unlocked by a master password that you will be \
+         asked for (once per session) whenever a saved password is \
+         needed:\n> "
+    );
+    io::stdout().flush().map_err(Error::StdoutError)?;
+    let mut yes_no = String::with_capacity(4);
+    io::stdin()
+        .read_line(&mut yes_no)
+        .map_err(Error::StdinError)?;
+    yes_no.make_ascii_lowercase();
+    loop {
+        let yes_no_trimmed = yes_no.as_str().trim();
+        if yes_no_trimmed == "yes" || yes_no_trimmed == "no" {
+            println!();
+
+            return Ok(Config {
+                store_passwords: yes_no_trimmed == "yes",
+                ..Config::new(
+                    install_dir.trim(),
+                    config_path
+                        .as_ref()
+                        .parent()
+                        .ok_or_else(|| {
+                            Error::BadConfigPath(
+                                config_path.as_ref().to_owned(),
+                            )
+                        })?
+                        .join("cache"),
+                )
+            });
+        }
+
+        print!("Please enter \"yes\" or \"no\" (without quotes):\n> ");
+        io::stdout().flush().map_err(Error::StdoutError)?;
+        yes_no.clear();
+        io::stdin()
+            .read_line(&mut yes_no)
+            .map_err(Error::StdinError)?;
+        yes_no.make_ascii_lowercase();
+    }
+}
@@ -0,0 +1,235 @@
+//! Where the manifest and the files on the CDN are actually fetched from.
+//! Mirrors whose URIs are `http://` or `https://` are fetched over HTTP(S),
+//! and anything else (a `file://` URI, or just a path) is read straight from
+//! the filesystem, e.g. from a USB stick or a network mount.
+
+use crate::{error::Error, retry, util};
+use reqwest::blocking as rb;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// A way of getting at the manifest and at the files on the CDN.
+pub trait Transport {
+    /// Fetches the whole manifest at `uri`.
+    fn fetch_manifest(&self, uri: &str) -> Result<String, Error>;
+
+    /// Downloads the file at `uri` into `dest` (which is at `dest_path`),
+    /// returning how many bytes were downloaded.
+    fn download(
+        &self,
+        uri: &str,
+        dest: &mut File,
+        dest_path: &Path,
+    ) -> Result<u64, Error>;
+
+    /// The URI of the file named `file_name` on the CDN mirror at `cdn_uri`.
+    fn join(&self, cdn_uri: &str, file_name: &str) -> String {
+        let mut uri = String::with_capacity(cdn_uri.len() + file_name.len());
+        uri += cdn_uri;
+        uri += file_name;
+
+        uri
+    }
+}
+
+/// Reads mirrors that are directories on the local filesystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalTransport;
+
+/// The transport that `uri` should be fetched with.
+pub fn for_uri<'a>(client: &'a rb::Client, uri: &str) -> &'a dyn Transport {
+    if is_http(uri) {
+        client
+    } else {
+        &LocalTransport
+    }
+}
+
+fn is_http(uri: &str) -> bool {
+    let scheme = uri.split("://").next().unwrap_or("");
+
+    uri.contains("://")
+        && (scheme.eq_ignore_ascii_case("http")
+            || scheme.eq_ignore_ascii_case("https"))
+}
+
+/// The path that a `file://` URI (or a plain path) points to. Only local
+/// files are supported, so the URI's host has to be empty or `localhost`.
+pub fn local_path(uri: &str) -> Result<PathBuf, Error> {
+    let rest = match uri.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("file://") => &uri[7..],
+        _ => return Ok(PathBuf::from(uri)),
+    };
+
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if !(host.is_empty() || host.eq_ignore_ascii_case("localhost")) {
+        return Err(Error::BadFileUri(
+            uri.to_owned(),
+            "only local files are supported",
+        ));
+    }
+    let path = percent_decode(path).ok_or_else(|| {
+        Error::BadFileUri(uri.to_owned(), "bad percent-encoding")
+    })?;
+
+    // `file:///C:/...`
+    #[cfg(windows)]
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => {
+            path[1..].to_owned()
+        }
+        _ => path,
+    };
+
+    Ok(PathBuf::from(path))
+}
+
+/// Decodes `%XX` escapes, as long as the result is valid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+impl Transport for rb::Client {
+    fn fetch_manifest(&self, uri: &str) -> Result<String, Error> {
+        let manifest_resp =
+            self.get(uri).send().map_err(Error::ManifestRequestError)?;
+        if !manifest_resp.status().is_success() {
+            return Err(Error::ManifestRequestStatusError(
+                manifest_resp.status(),
+                retry::retry_after(&manifest_resp),
+            ));
+        }
+
+        manifest_resp.text().map_err(Error::ManifestRequestError)
+    }
+
+    fn download(
+        &self,
+        uri: &str,
+        dest: &mut File,
+        dest_path: &Path,
+    ) -> Result<u64, Error> {
+        let mut dl_resp =
+            self.get(uri).send().map_err(Error::DownloadRequestError)?;
+        if !dl_resp.status().is_success() {
+            return Err(Error::DownloadRequestStatusError(
+                dl_resp.status(),
+                retry::retry_after(&dl_resp),
+            ));
+        }
+
+        dl_resp.copy_to(dest).map_err(|re| {
+            Error::CopyIntoFileError(dest_path.to_path_buf(), re)
+        })
+    }
+}
+
+impl Transport for LocalTransport {
+    fn fetch_manifest(&self, uri: &str) -> Result<String, Error> {
+        let path = local_path(uri)?;
+        let mut manifest_text = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut manifest_text))
+            .map_err(|ioe| Error::ManifestReadError(path, ioe))?;
+
+        Ok(manifest_text)
+    }
+
+    fn download(
+        &self,
+        uri: &str,
+        dest: &mut File,
+        dest_path: &Path,
+    ) -> Result<u64, Error> {
+        let path = local_path(uri)?;
+        let mut src = util::open_file(&path)?;
+
+        // Tell apart which side of the copy failed
+        let mut buf = vec![0u8; 0x20_00];
+        let mut copied = 0;
+        loop {
+            let n = match src.read(&mut buf) {
+                Ok(0) => return Ok(copied),
+                Ok(n) => n,
+                Err(ioe) if ioe.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(ioe) => return Err(Error::FileReadError(path, ioe)),
+            };
+            dest.write_all(&buf[..n]).map_err(|ioe| {
+                Error::FileWriteError(dest_path.to_path_buf(), ioe)
+            })?;
+            copied += n as u64;
+        }
+    }
+
+    fn join(&self, cdn_uri: &str, file_name: &str) -> String {
+        let mut uri =
+            String::with_capacity(cdn_uri.len() + 1 + file_name.len());
+        uri += cdn_uri;
+        if !uri.ends_with('/') && !uri.ends_with(std::path::MAIN_SEPARATOR) {
+            uri.push('/');
+        }
+        uri += file_name;
+
+        uri
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_paths_are_left_alone() {
+        assert_eq!(
+            local_path("/srv/My%20Mirror/").unwrap(),
+            PathBuf::from("/srv/My%20Mirror/"),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_uris() {
+        assert_eq!(
+            local_path("file:///srv/mirror/").unwrap(),
+            PathBuf::from("/srv/mirror/"),
+        );
+        assert_eq!(
+            local_path("FILE://localhost/srv/mirror/").unwrap(),
+            PathBuf::from("/srv/mirror/"),
+        );
+        assert_eq!(
+            local_path("file:///mnt/My%20USB/caf%C3%A9").unwrap(),
+            PathBuf::from("/mnt/My USB/café"),
+        );
+    }
+
+    #[test]
+    fn bad_file_uris() {
+        assert!(local_path("file://example.com/srv/mirror/").is_err());
+        assert!(local_path("file:///mnt/100%").is_err());
+        assert!(local_path("file:///mnt/%zz").is_err());
+        assert!(local_path("file:///mnt/%FF").is_err());
+    }
+}
@@ -1,16 +1,25 @@
-use crate::{config::Config, error::Error, patch, util};
+use crate::{
+    config::Config,
+    error::Error,
+    history, hooks,
+    packs::{self, Overrides},
+    patch,
+    retry::RetryPolicy,
+    transport, util,
+};
 use bzip2::write::BzDecoder as BzWriteDecoder;
 use reqwest::blocking as rb;
-use serde_json;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use std::{
+    fmt,
     fs::{self, File},
     io::{self, prelude::*},
-    num::NonZeroUsize,
     path::{Path, PathBuf},
+    time::Instant,
 };
 
-pub const BUFFER_SIZE: usize = 0x20_00;
+const BUFFER_SIZE: usize = 0x20_00;
 #[cfg(target_os = "linux")]
 pub const OS_AND_ARCH: &str = "linux2";
 #[cfg(target_os = "macos")]
@@ -20,19 +29,156 @@ pub const OS_AND_ARCH: &str = "win64";
 #[cfg(all(windows, target_arch = "x86"))]
 pub const OS_AND_ARCH: &str = "win32";
 
+/// What happened over the course of a call to `update`.
+#[derive(Debug, Default)]
+pub struct UpdateSummary {
+    /// The name of each file that was downloaded from the CDN, alongside the
+    /// mirror that it was successfully downloaded from.
+    pub downloads: Vec<(String, String)>,
+    /// The mirror that the manifest was downloaded from.
+    pub manifest_uri: String,
+    /// Names of files that were successfully patched.
+    pub patched: Vec<String>,
+    /// Names of files that were successfully downloaded in full.
+    pub downloaded: Vec<String>,
+    /// Names of files that failed to update, alongside why. Unless the
+    /// update was told to keep going, there is at most one of these.
+    pub failed: Vec<(String, Error)>,
+    /// For dry runs, the name of each file that would have been updated,
+    /// alongside how.
+    pub pending: Vec<(String, PendingUpdate)>,
+    /// Names of files that were held back by an exclude pattern, or because
+    /// they are among the deployed content packs.
+    pub held: Vec<String>,
+    /// Assets that were changed by this update, but are overridden by an
+    /// enabled content pack: the name of the file that the asset is in, the
+    /// asset's name, and the names of the packs.
+    pub pack_conflicts: Vec<(String, String, Vec<String>)>,
+    pub stats: UpdateStats,
+}
+
+/// Numbers describing a call to `update`, as reported afterwards and recorded
+/// in the update history.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct UpdateStats {
+    /// Files in the manifest that are supported by this OS & architecture.
+    pub files_checked: usize,
+    pub files_up_to_date: usize,
+    /// Files in the manifest that aren't for this OS & architecture, or that
+    /// weren't selected by the include patterns.
+    pub files_skipped: usize,
+    /// Files that were held back by an exclude pattern.
+    pub files_held: usize,
+    /// Everything that was transferred from the CDN, including patches and
+    /// any attempts that had to be retried.
+    pub bytes_downloaded: u64,
+    /// What the files that were downloaded in full cost, compressed.
+    pub bytes_full_download: u64,
+    /// What the patches that were downloaded cost, compressed, whether or not
+    /// they could be applied.
+    pub bytes_patches: u64,
+    /// The uncompressed size of each file that was patched. The CDN isn't
+    /// asked how large their compressed full downloads would have been, so
+    /// this is only an upper bound on what patching saved.
+    pub bytes_patched_estimate: u64,
+    pub retries: u64,
+    pub manifest_secs: f64,
+    /// Time spent SHA1 hashing files, both local and downloaded.
+    pub checking_secs: f64,
+    pub downloading_secs: f64,
+    pub extracting_secs: f64,
+    pub patching_secs: f64,
+    pub total_secs: f64,
+}
+
+/// How a file that is out of date would be brought up to date.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PendingUpdate {
+    /// The file doesn't exist yet, and would be downloaded.
+    Download,
+    /// The file is an older version, and would be patched.
+    Patch,
+    /// The file doesn't match any version known to the manifest, and would
+    /// be downloaded from scratch.
+    Redownload,
+}
+
+/// Which of the files in the manifest an update is allowed to touch, in terms
+/// of globs (see `util::glob_match`) over their names.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    /// If nonempty, only files matching at least one of these are updated.
+    pub include: Vec<String>,
+    /// Files matching any of these are held back.
+    pub exclude: Vec<String>,
+}
+
+impl FileFilter {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            include: config.include.clone(),
+            exclude: config.exclude.clone(),
+        }
+    }
+
+    pub fn is_included(&self, file_name: &str) -> bool {
+        self.include.is_empty()
+            || self.include.iter().any(|g| util::glob_match(g, file_name))
+    }
+
+    pub fn is_held(&self, file_name: &str) -> bool {
+        self.exclude.iter().any(|g| util::glob_match(g, file_name))
+    }
+
+    /// Whether `file_name` may be updated at all.
+    pub fn selects(&self, file_name: &str) -> bool {
+        self.is_included(file_name) && !self.is_held(file_name)
+    }
+}
+
+impl fmt::Display for PendingUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Download => "missing",
+            Self::Patch => "patch available",
+            Self::Redownload => "unknown local version",
+        })
+    }
+}
+
+/// Brings the files in `config.install_dir` up to date with the manifest,
+/// touching only those that `filter` selects. If `dry`, nothing is changed,
+/// and the returned summary's `pending` instead says which files would be
+/// updated, and how.
+///
+/// Progress is printed to stdout unless `quiet`; warnings and failures are
+/// always printed to stderr.
 pub fn update(
     config: &Config,
     client: &rb::Client,
     quiet: bool,
-    max_tries: NonZeroUsize,
+    retry_policy: &RetryPolicy,
     dry: bool,
-) -> Result<(), Error> {
+    filter: &FileFilter,
+    keep_going: bool,
+) -> Result<UpdateSummary, Error> {
+    config.check_mirrors()?;
     ensure_dir(&config.install_dir)?;
     if !dry {
         ensure_dir(&config.cache_dir)?;
     }
 
-    let manifest_map = match get_manifest(config, client, quiet, max_tries)? {
+    let mut summary = UpdateSummary::default();
+    let start = Instant::now();
+    let initial_retries = retry_policy.retries();
+
+    let (manifest, manifest_uri) =
+        get_manifest(config, client, quiet, retry_policy)?;
+    summary.manifest_uri = manifest_uri.to_owned();
+    summary.stats.manifest_secs = start.elapsed().as_secs_f64();
+    let manifest_map = match &manifest {
         serde_json::Value::Object(m) => m,
         _ => {
             return Err(Error::BadManifestFormat(
@@ -45,182 +191,132 @@ pub fn update(
         println!("Downloaded manifest successfully!");
     }
 
-    let mut install_dir = config.install_dir.clone();
-    for (i, (file_name, file_obj)) in manifest_map.iter().enumerate() {
-        if !quiet {
-            println!(
-                "[{:2}/{}] Checking for updates for {}",
-                i + 1,
-                manifest_map.len(),
+    if !dry {
+        hooks::run_pre_update(config, quiet, &manifest, &summary)?;
+    }
+
+    let overrides = if dry {
+        Overrides::new()
+    } else {
+        packs::overrides(config)
+    };
+
+    let res = update_files(
+        config,
+        client,
+        quiet,
+        retry_policy,
+        dry,
+        filter,
+        keep_going,
+        &overrides,
+        manifest_map,
+        &mut summary,
+    );
+    let succeeded = res.is_ok() && summary.failed.is_empty();
+    summary.stats.retries = retry_policy.retries() - initial_retries;
+    summary.stats.total_secs = start.elapsed().as_secs_f64();
+
+    if !dry {
+        for (file_name, asset, pack_names) in summary.pack_conflicts.iter() {
+            eprintln!(
+                "Warning: {} in {} was changed by this update, but is \
+                 overridden by the {} content pack(s)",
+                asset,
                 file_name,
+                pack_names.join(", "),
             );
         }
+        if !quiet {
+            history::print_report(&summary);
+        }
+        if let Err(e) = history::record(config, &summary, succeeded) {
+            eprintln!("Failed to record this update in the history: {}", e);
+        }
 
-        let file_map = if let serde_json::Value::Object(m) = file_obj {
-            m
-        } else {
-            return Err(Error::BadManifestFormat(
-                "Expected Object at 2nd-to-top level".to_owned(),
-            ));
-        };
+        hooks::run_post_update(config, quiet, &manifest, &summary, succeeded);
+    }
 
-        let supported_archs = match file_map.get("only").ok_or_else(|| {
-            Error::BadManifestFormat("Missing the \"only\" key".to_owned())
-        })? {
-            serde_json::Value::Array(v) => v,
-            _ => {
-                return Err(Error::BadManifestFormat(
-                    "Expected \"only\"'s value to be an Array".to_owned(),
-                ))
-            }
-        };
-        let mut supported_by_this_arch = false;
-        for arch_val in supported_archs {
-            match arch_val {
-                serde_json::Value::String(s) => {
-                    if OS_AND_ARCH == s {
-                        supported_by_this_arch = true;
-
-                        break;
-                    }
-                }
-                _ => {
-                    return Err(Error::BadManifestFormat(
-                        "Expected OS & architecture values to be Strings"
-                            .to_owned(),
-                    ))
-                }
-            }
-        }
+    if keep_going && !summary.failed.is_empty() {
+        print_failures(&summary.failed);
+    }
 
-        if !supported_by_this_arch {
-            if !quiet {
-                println!(
-                    "        Not supported by this OS & architecture, \
-                     skipping..."
-                );
-            }
+    res?;
+    if summary.failed.is_empty() {
+        Ok(summary)
+    } else if keep_going {
+        Err(Error::UpdateIncomplete(summary.failed.len()))
+    } else {
+        // The one and only failure, which stopped the update in its tracks.
+        Err(summary.failed.swap_remove(0).1)
+    }
+}
 
-            continue;
-        }
+fn print_failures(failed: &[(String, Error)]) {
+    let name_width = failed.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+
+    eprintln!("\n{} file(s) failed to update:", failed.len());
+    for (file_name, e) in failed.iter() {
+        // Keep multi-line errors lined up in their column.
+        let continuation = format!("\n    {:w$}  ", "", w = name_width);
+        eprintln!(
+            "    {:w$}  {}",
+            file_name,
+            e.to_string().replace('\n', &continuation),
+            w = name_width,
+        );
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
+fn update_files(
+    config: &Config,
+    client: &rb::Client,
+    quiet: bool,
+    retry_policy: &RetryPolicy,
+    dry: bool,
+    filter: &FileFilter,
+    keep_going: bool,
+    overrides: &Overrides,
+    manifest_map: &serde_json::Map<String, serde_json::Value>,
+    summary: &mut UpdateSummary,
+) -> Result<(), Error> {
+    for (i, (file_name, file_obj)) in manifest_map.iter().enumerate() {
         if !quiet {
-            println!("        Checking to see if file already exists...");
+            println!(
+                "[{:2}/{}] Checking for updates for {}",
+                i + 1,
+                manifest_map.len(),
+                file_name,
+            );
         }
 
-        install_dir.push(file_name);
+        if let Err(e) = update_file(
+            config,
+            client,
+            quiet,
+            retry_policy,
+            dry,
+            filter,
+            overrides,
+            file_name,
+            file_obj,
+            summary,
+        ) {
+            if !keep_going {
+                summary.failed.push((file_name.clone(), e));
 
-        let already_existing_file = match File::open(&install_dir) {
-            Ok(f) => Some(f),
-            Err(ioe) => match ioe.kind() {
-                io::ErrorKind::NotFound => {
-                    if !quiet {
-                        if dry {
-                            println!(
-                                "        File doesn't exist! Suppressing \
-                                 download because this is a dry run."
-                            );
-                            install_dir.pop();
-
-                            continue;
-                        } else {
-                            println!(
-                                "        File doesn't exist, downloading \
-                                 from scratch..."
-                            );
-                        }
-                    }
+                return Ok(());
+            }
 
-                    let mut file_buf = [0u8; BUFFER_SIZE];
-                    let compressed_file_name = file_map
-                        .get("dl")
-                        .ok_or_else(|| {
-                            Error::BadManifestFormat(
-                                "Expected \"dl\"".to_owned(),
-                            )
-                        })
-                        .and_then(|val| match val {
-                            serde_json::Value::String(s) => Ok(s),
-                            _ => Err(Error::BadManifestFormat(
-                                "Expected \"dl\" to be a String".to_owned(),
-                            )),
-                        })?;
-                    let compressed_sha = file_map
-                        .get("compHash")
-                        .ok_or_else(|| {
-                            Error::BadManifestFormat(
-                                "Expected \"compHash\"".to_owned(),
-                            )
-                        })
-                        .and_then(|val| match val {
-                            serde_json::Value::String(s) => {
-                                sha_from_hash_str(s)
-                            }
-                            _ => Err(Error::BadManifestFormat(
-                                "Expected \"compHash\" to be a String"
-                                    .to_owned(),
-                            )),
-                        })?;
-                    let decompressed_sha = file_map
-                        .get("hash")
-                        .ok_or_else(|| {
-                            Error::BadManifestFormat(
-                                "Expected \"hash\"".to_owned(),
-                            )
-                        })
-                        .and_then(|val| match val {
-                            serde_json::Value::String(s) => {
-                                sha_from_hash_str(s)
-                            }
-                            _ => Err(Error::BadManifestFormat(
-                                "Expected \"hash\" to be a String".to_owned(),
-                            )),
-                        })?;
-
-                    download_file(
-                        false,
-                        &mut file_buf,
-                        config,
-                        client,
-                        quiet,
-                        max_tries,
-                        compressed_file_name,
-                        file_name,
-                        &compressed_sha,
-                        &decompressed_sha,
-                    )?;
-
-                    None
-                }
-                io::ErrorKind::PermissionDenied => {
-                    return Err(Error::PermissionDenied(
-                        format!("opening {:?}", install_dir),
-                        ioe,
-                    ))
-                }
-                _ => {
-                    return Err(Error::UnknownIoError(
-                        format!("opening {:?}", install_dir),
-                        ioe,
-                    ))
-                }
-            },
-        };
-        if let Some(f) = already_existing_file {
-            update_existing_file(
-                config,
-                client,
-                quiet,
-                dry,
-                max_tries,
-                f,
-                file_map,
-                file_name,
-                &install_dir,
-            )?;
+            eprintln!("        Failed to update {}: {}", file_name, e);
+            summary.failed.push((file_name.clone(), e));
         }
+    }
 
-        install_dir.pop();
+    if !quiet && !filter.include.is_empty() && summary.stats.files_checked == 0
+    {
+        eprintln!("No files in the manifest match the given pattern(s)!");
     }
 
     #[cfg(unix)]
@@ -236,20 +332,24 @@ pub fn update(
         #[cfg(target_os = "macos")]
         const EXE_NAME: &str = "Toontown Rewritten";
 
+        if !filter.selects(EXE_NAME) {
+            return Ok(());
+        }
+
         if !quiet {
             println!("Making sure {} is executable...", EXE_NAME);
         }
 
-        install_dir.push(EXE_NAME);
-        let mut ttrengine_perms = fs::metadata(&install_dir)
+        let exe_path = config.install_dir.join(EXE_NAME);
+        let mut ttrengine_perms = fs::metadata(&exe_path)
             .map_err(|ioe| match ioe.kind() {
                 io::ErrorKind::NotFound => Error::MissingFile(EXE_NAME),
                 io::ErrorKind::PermissionDenied => Error::PermissionDenied(
-                    format!("obtaining metadata for {:?}", install_dir),
+                    format!("obtaining metadata for {:?}", exe_path),
                     ioe,
                 ),
                 _ => Error::UnknownIoError(
-                    format!("obtaining metadata for {:?}", install_dir),
+                    format!("obtaining metadata for {:?}", exe_path),
                     ioe,
                 ),
             })?
@@ -264,8 +364,8 @@ pub fn update(
             }
 
             ttrengine_perms.set_mode(ttrengine_mode | 0o700);
-            fs::set_permissions(&install_dir, ttrengine_perms)
-                .map_err(|ioe| Error::PermissionsSetError(install_dir, ioe))?;
+            fs::set_permissions(&exe_path, ttrengine_perms)
+                .map_err(|ioe| Error::PermissionsSetError(exe_path, ioe))?;
 
             if !quiet {
                 println!("{} is now executable!", EXE_NAME);
@@ -278,26 +378,241 @@ pub fn update(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+fn update_file(
+    config: &Config,
+    client: &rb::Client,
+    quiet: bool,
+    retry_policy: &RetryPolicy,
+    dry: bool,
+    filter: &FileFilter,
+    overrides: &Overrides,
+    file_name: &str,
+    file_obj: &serde_json::Value,
+    summary: &mut UpdateSummary,
+) -> Result<(), Error> {
+    let file_map = if let serde_json::Value::Object(m) = file_obj {
+        m
+    } else {
+        return Err(Error::BadManifestFormat(
+            "Expected Object at 2nd-to-top level".to_owned(),
+        ));
+    };
+
+    let supported_archs = match file_map.get("only").ok_or_else(|| {
+        Error::BadManifestFormat("Missing the \"only\" key".to_owned())
+    })? {
+        serde_json::Value::Array(v) => v,
+        _ => {
+            return Err(Error::BadManifestFormat(
+                "Expected \"only\"'s value to be an Array".to_owned(),
+            ))
+        }
+    };
+    let mut supported_by_this_arch = false;
+    for arch_val in supported_archs {
+        match arch_val {
+            serde_json::Value::String(s) => {
+                if OS_AND_ARCH == s {
+                    supported_by_this_arch = true;
+
+                    break;
+                }
+            }
+            _ => {
+                return Err(Error::BadManifestFormat(
+                    "Expected OS & architecture values to be Strings"
+                        .to_owned(),
+                ))
+            }
+        }
+    }
+
+    if !supported_by_this_arch {
+        if !quiet {
+            println!(
+                "        Not supported by this OS & architecture, \
+                 skipping..."
+            );
+        }
+        summary.stats.files_skipped += 1;
+
+        return Ok(());
+    }
+
+    if packs::is_pack_path(file_name) {
+        if !quiet {
+            println!("        Among the content packs, skipping...");
+        }
+        summary.held.push(file_name.to_owned());
+        summary.stats.files_held += 1;
+
+        return Ok(());
+    }
+    if filter.is_held(file_name) {
+        if !quiet {
+            println!("        Held back by an exclude pattern, skipping...");
+        }
+        summary.held.push(file_name.to_owned());
+        summary.stats.files_held += 1;
+
+        return Ok(());
+    }
+    if !filter.is_included(file_name) {
+        if !quiet {
+            println!(
+                "        Not selected by the include patterns, skipping..."
+            );
+        }
+        summary.stats.files_skipped += 1;
+
+        return Ok(());
+    }
+    summary.stats.files_checked += 1;
+
+    if !quiet {
+        println!("        Checking to see if file already exists...");
+    }
+
+    let file_path = config.install_dir.join(file_name);
+
+    let already_existing_file = match File::open(&file_path) {
+        Ok(f) => Some(f),
+        Err(ioe) => match ioe.kind() {
+            io::ErrorKind::NotFound => {
+                if dry {
+                    if !quiet {
+                        println!(
+                            "        File doesn't exist! Suppressing \
+                             download because this is a dry run."
+                        );
+                    }
+                    summary
+                        .pending
+                        .push((file_name.to_owned(), PendingUpdate::Download));
+
+                    return Ok(());
+                } else if !quiet {
+                    println!(
+                        "        File doesn't exist, downloading from \
+                         scratch..."
+                    );
+                }
+
+                let mut file_buf = [0u8; BUFFER_SIZE];
+                let compressed_file_name = file_map
+                    .get("dl")
+                    .ok_or_else(|| {
+                        Error::BadManifestFormat("Expected \"dl\"".to_owned())
+                    })
+                    .and_then(|val| match val {
+                        serde_json::Value::String(s) => Ok(s),
+                        _ => Err(Error::BadManifestFormat(
+                            "Expected \"dl\" to be a String".to_owned(),
+                        )),
+                    })?;
+                let compressed_sha = file_map
+                    .get("compHash")
+                    .ok_or_else(|| {
+                        Error::BadManifestFormat(
+                            "Expected \"compHash\"".to_owned(),
+                        )
+                    })
+                    .and_then(|val| match val {
+                        serde_json::Value::String(s) => sha_from_hash_str(s),
+                        _ => Err(Error::BadManifestFormat(
+                            "Expected \"compHash\" to be a String".to_owned(),
+                        )),
+                    })?;
+                let decompressed_sha = file_map
+                    .get("hash")
+                    .ok_or_else(|| {
+                        Error::BadManifestFormat(
+                            "Expected \"hash\"".to_owned(),
+                        )
+                    })
+                    .and_then(|val| match val {
+                        serde_json::Value::String(s) => sha_from_hash_str(s),
+                        _ => Err(Error::BadManifestFormat(
+                            "Expected \"hash\" to be a String".to_owned(),
+                        )),
+                    })?;
+
+                download_file(
+                    false,
+                    &mut file_buf,
+                    config,
+                    client,
+                    quiet,
+                    retry_policy,
+                    compressed_file_name,
+                    file_name,
+                    &compressed_sha,
+                    &decompressed_sha,
+                    summary,
+                )?;
+                summary.downloaded.push(file_name.to_owned());
+
+                None
+            }
+            io::ErrorKind::PermissionDenied => {
+                return Err(Error::PermissionDenied(
+                    format!("opening {:?}", file_path),
+                    ioe,
+                ))
+            }
+            _ => {
+                return Err(Error::UnknownIoError(
+                    format!("opening {:?}", file_path),
+                    ioe,
+                ))
+            }
+        },
+    };
+    if let Some(f) = already_existing_file {
+        update_existing_file(
+            config,
+            client,
+            quiet,
+            dry,
+            retry_policy,
+            overrides,
+            f,
+            file_map,
+            file_name,
+            &file_path,
+            summary,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
     config: &Config,
     client: &rb::Client,
     quiet: bool,
     dry: bool,
-    max_tries: NonZeroUsize,
+    retry_policy: &RetryPolicy,
+    overrides: &Overrides,
     mut already_existing_file: File,
     file_map: &serde_json::Map<String, serde_json::Value>,
     file_name: S,
     full_file_path: P,
+    summary: &mut UpdateSummary,
 ) -> Result<(), Error> {
     if !quiet {
         println!("        File exists, checking SHA1 hash...");
     }
 
     let mut file_buf = [0u8; BUFFER_SIZE];
+    let hash_start = Instant::now();
     let initial_sha = sha_of_reader(&mut already_existing_file, &mut file_buf)
         .map_err(|ioe| {
             Error::FileReadError(full_file_path.as_ref().to_path_buf(), ioe)
         })?;
+    summary.stats.checking_secs += hash_start.elapsed().as_secs_f64();
 
     let manifest_sha = sha_from_hash_str(match file_map.get("hash") {
         Some(serde_json::Value::String(s)) => s,
@@ -317,6 +632,7 @@ fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
         if !quiet {
             println!("        SHA1 hash matches!");
         }
+        summary.stats.files_up_to_date += 1;
 
         return Ok(());
     }
@@ -333,6 +649,14 @@ fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
         println!("\n        Checking for a patch...");
     }
 
+    // To tell whether or not this update changes anything that a content
+    // pack overrides.
+    let digests_before = if dry {
+        Default::default()
+    } else {
+        packs::overridden_digests(&full_file_path, overrides)
+    };
+
     let patches_map = file_map
         .get("patches")
         .and_then(|val| match val {
@@ -348,6 +672,7 @@ fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
         })?;
 
     let mut did_patch = false;
+    let mut patch_rejected = false;
     for (manifest_sha_str, patch_obj) in patches_map.iter() {
         if sha_from_hash_str(manifest_sha_str)? != initial_sha {
             continue;
@@ -374,30 +699,33 @@ fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
                 )
             })?;
 
-        if !quiet {
-            if dry {
+        if dry {
+            if !quiet {
                 println!(
                     "        Found a patch! Suppressed downloading patch \
                      because this is a dry run."
                 );
-
-                return Ok(());
-            } else {
-                println!("        Found a patch! Downloading it...");
             }
+            summary
+                .pending
+                .push((file_name.as_ref().to_owned(), PendingUpdate::Patch));
+
+            return Ok(());
+        } else if !quiet {
+            println!("        Found a patch! Downloading it...");
         }
 
         let mut extracted_patch_file_name =
             String::with_capacity(patch_file_name.len() + ".extracted".len());
         extracted_patch_file_name += patch_file_name;
         extracted_patch_file_name += ".extracted";
-        let extracted_patch_path = download_file(
+        let extracted_patch_path = match download_file(
             true,
             &mut file_buf,
             config,
             client,
             quiet,
-            max_tries,
+            retry_policy,
             patch_file_name,
             &extracted_patch_file_name,
             &patch_map
@@ -426,37 +754,107 @@ fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
                         "Expected \"patchHash\" to be a String".to_owned(),
                     )),
                 })?,
-        )?;
+            summary,
+        ) {
+            Ok(path) => path,
+            // The whole file can still be downloaded instead.
+            Err(e) => {
+                eprintln!(
+                    "        Warning: failed to download the patch for \
+                     {}:\n        {}",
+                    file_name.as_ref(),
+                    e,
+                );
+                eprintln!("        Falling back to a full download...");
+                patch_rejected = true;
+
+                break;
+            }
+        };
 
         if !quiet {
             println!("        Applying patch...");
         }
 
-        patch::patch_file(&extracted_patch_path, full_file_path)?;
+        let patch_start = Instant::now();
+        // Broken patches are caught before the old file is even looked at
+        let patch_res =
+            patch::inspect_file(&extracted_patch_path).and_then(|_| {
+                patch::patch_file(
+                    &extracted_patch_path,
+                    &full_file_path,
+                    |tmp| {
+                        if sha_of_file_by_path(tmp, &mut file_buf)?
+                            == manifest_sha
+                        {
+                            Ok(())
+                        } else {
+                            Err(Error::HashMismatch(
+                                tmp.to_path_buf(),
+                                manifest_sha,
+                            ))
+                        }
+                    },
+                )
+            });
+        summary.stats.patching_secs += patch_start.elapsed().as_secs_f64();
+
+        // A patch that can't be applied isn't the end of the world, since
+        // the whole file can still be downloaded.
+        if let Err(e) = patch_res {
+            if let Error::HashMismatch(_, _) = e {
+                eprintln!(
+                    "        Warning: the patched {} doesn't match the \
+                     manifest's SHA1 hash!",
+                    file_name.as_ref(),
+                );
+            } else {
+                eprintln!(
+                    "        Warning: failed to patch {}:\n        {}",
+                    file_name.as_ref(),
+                    e,
+                );
+            }
+            eprintln!(
+                "        Discarding the patch, and falling back to a full \
+                 download..."
+            );
+            let _ = fs::remove_file(&extracted_patch_path);
+            patch_rejected = true;
+
+            break;
+        }
 
         if !quiet {
             println!("        File patched successfully!");
         }
 
+        if let Ok(md) = fs::metadata(&full_file_path) {
+            summary.stats.bytes_patched_estimate += md.len();
+        }
+
+        summary.patched.push(file_name.as_ref().to_owned());
         did_patch = true;
 
         break;
     }
 
     if !did_patch {
-        if !quiet {
-            if dry {
+        if dry {
+            if !quiet {
                 println!(
                     "        No patches found! Suppressing download because \
                      this is a dry run."
                 );
-
-                return Ok(());
-            } else {
-                println!(
-                    "        No patches found, downloading from scratch..."
-                );
             }
+            summary.pending.push((
+                file_name.as_ref().to_owned(),
+                PendingUpdate::Redownload,
+            ));
+
+            return Ok(());
+        } else if !quiet && !patch_rejected {
+            println!("        No patches found, downloading from scratch...");
         }
 
         let compressed_file_name = file_map
@@ -488,82 +886,77 @@ fn update_existing_file<S: AsRef<str>, P: AsRef<Path>>(
             config,
             client,
             quiet,
-            max_tries,
+            retry_policy,
             compressed_file_name,
-            file_name,
+            &file_name,
             &compressed_sha,
             &manifest_sha,
+            summary,
         )?;
+        summary.downloaded.push(file_name.as_ref().to_owned());
+    }
+
+    if !digests_before.is_empty() {
+        let digests_after =
+            packs::overridden_digests(&full_file_path, overrides);
+        for asset in packs::changed_assets(&digests_before, &digests_after) {
+            let pack_names =
+                overrides.get(&asset).cloned().unwrap_or_default();
+            summary.pack_conflicts.push((
+                file_name.as_ref().to_owned(),
+                asset,
+                pack_names,
+            ));
+        }
     }
 
     Ok(())
 }
 
-fn get_manifest(
-    config: &Config,
+/// Returns the manifest, along with the mirror that it came from.
+pub fn get_manifest<'a>(
+    config: &'a Config,
     client: &rb::Client,
     quiet: bool,
-    max_tries: NonZeroUsize,
-) -> Result<serde_json::Value, Error> {
-    let mut last_err = None;
-
-    for i in 1..=max_tries.get() {
-        let mut handle_retry = |e| {
-            eprintln!(
-                "{}{}",
-                e,
-                if i < max_tries.get() {
-                    ", retrying..."
-                } else {
-                    ", no more attempts remaining!"
-                },
-            );
-            last_err = Some(e);
-        };
-
-        if !quiet {
-            println!("Downloading manifest [attempt {}/{}]...", i, max_tries);
-        }
+    retry_policy: &RetryPolicy,
+) -> Result<(serde_json::Value, &'a str), Error> {
+    if config.manifest_uris.is_empty() {
+        return Err(Error::NoMirrors("manifest_uris"));
+    }
 
-        let manifest_resp = match client
-            .get(&config.manifest_uri)
-            .send()
-            .map_err(Error::ManifestRequestError)
-        {
-            Ok(mr) => mr,
-            Err(e) => {
-                handle_retry(e);
+    let (manifest, m) = retry_policy.run_with_failover(
+        "",
+        config.manifest_uris.len(),
+        |i, m| {
+            let manifest_uri = &config.manifest_uris[m];
 
-                continue;
+            if !quiet {
+                if config.manifest_uris.len() > 1 {
+                    println!(
+                        "Downloading manifest from {} [attempt {}/{}]...",
+                        manifest_uri, i, retry_policy.max_tries,
+                    );
+                } else {
+                    println!(
+                        "Downloading manifest [attempt {}/{}]...",
+                        i, retry_policy.max_tries,
+                    );
+                }
             }
-        };
-        if !manifest_resp.status().is_success() {
-            handle_retry(Error::ManifestRequestStatusError(
-                manifest_resp.status(),
-            ));
 
-            continue;
-        }
-
-        let manifest_text =
-            match manifest_resp.text().map_err(Error::ManifestRequestError) {
-                Ok(mt) => mt,
-                Err(e) => {
-                    handle_retry(e);
+            let manifest_text = transport::for_uri(client, manifest_uri)
+                .fetch_manifest(manifest_uri)?;
 
-                    continue;
-                }
-            };
+            serde_json::from_str(&manifest_text)
+                .map_err(Error::DeserializeError)
+        },
+    )?;
 
-        match serde_json::from_str(&manifest_text)
-            .map_err(Error::DeserializeError)
-        {
-            Err(e) => handle_retry(e),
-            ok => return ok,
-        }
+    if !quiet && config.manifest_uris.len() > 1 {
+        println!("Got manifest from {}", config.manifest_uris[m]);
     }
 
-    Err(last_err.unwrap_or_else(|| unreachable!()))
+    Ok((manifest, &config.manifest_uris[m]))
 }
 
 fn sha_of_reader<R: Read>(
@@ -602,7 +995,7 @@ fn sha_from_hash_str<S: AsRef<str>>(hash_str: S) -> Result<[u8; 20], Error> {
     let mut manifest_sha = [0u8; 20];
     for (i, &b) in hash_str.as_ref().as_bytes().iter().enumerate() {
         let nibble_val = match b {
-            b if b >= b'0' && b <= b'9' => b - b'0',
+            b @ b'0'..=b'9' => b - b'0',
             b'a' | b'A' => 0x0a,
             b'b' | b'B' => 0x0b,
             b'c' | b'C' => 0x0c,
@@ -624,8 +1017,14 @@ fn sha_from_hash_str<S: AsRef<str>>(hash_str: S) -> Result<[u8; 20], Error> {
 }
 
 /// Downloads to the cache if `to_cache`, otherwise downloads to the main
-/// installation directory. Returns the full path to the downloaded file on
+/// installation directory. The CDN mirrors are failed over in order, and the
+/// one that the file ended up coming from is recorded in `summary`, along with
+/// the relevant stats. Returns the full path to the downloaded file on
 /// success.
+///
+/// The compressed file always goes in the cache, and the decompressed file
+/// only ever appears under its real name once it is complete, synced to disk,
+/// and known to match `decompressed_sha`.
 #[allow(clippy::too_many_arguments)]
 fn download_file<S: AsRef<str>, T: AsRef<str>>(
     to_cache: bool,
@@ -633,28 +1032,15 @@ fn download_file<S: AsRef<str>, T: AsRef<str>>(
     config: &Config,
     client: &rb::Client,
     quiet: bool,
-    max_tries: NonZeroUsize,
+    retry_policy: &RetryPolicy,
     compressed_file_name: S,
     decompressed_file_name: T,
     compressed_sha: &[u8; 20],
     decompressed_sha: &[u8; 20],
+    summary: &mut UpdateSummary,
 ) -> Result<PathBuf, Error> {
-    let mut dl_uri = String::with_capacity(
-        config.cdn_uri.len() + compressed_file_name.as_ref().len(),
-    );
-    dl_uri += &config.cdn_uri;
-    dl_uri += compressed_file_name.as_ref();
-
-    let compressed_file_path = {
-        let mut pb = if to_cache {
-            config.cache_dir.clone()
-        } else {
-            config.install_dir.clone()
-        };
-        pb.push(compressed_file_name.as_ref());
-
-        pb
-    };
+    let compressed_file_path =
+        config.cache_dir.join(compressed_file_name.as_ref());
     let decompressed_file_path = {
         let mut pb = if to_cache {
             config.cache_dir.clone()
@@ -665,130 +1051,139 @@ fn download_file<S: AsRef<str>, T: AsRef<str>>(
 
         pb
     };
+    let temp_file_path = util::temp_path(&decompressed_file_path);
+
+    let stats = &mut summary.stats;
+    let (compressed_size, m) = retry_policy.run_with_failover(
+        "        ",
+        config.cdn_uris.len(),
+        |i, m| {
+            let cdn_uri = &config.cdn_uris[m];
+            let transport = transport::for_uri(client, cdn_uri);
+            let dl_uri =
+                transport.join(cdn_uri, compressed_file_name.as_ref());
 
-    let mut last_err = None;
-
-    for i in 1..=max_tries.get() {
-        let mut handle_retry = |e| {
-            eprintln!(
-                "        {}{}",
-                e,
-                if i < max_tries.get() {
-                    ", retrying..."
+            if !quiet {
+                if config.cdn_uris.len() > 1 {
+                    println!(
+                        "        Downloading {} from {} [attempt {}/{}]",
+                        compressed_file_name.as_ref(),
+                        cdn_uri,
+                        i,
+                        retry_policy.max_tries,
+                    );
                 } else {
-                    ", no more attempts remaining!"
-                },
-            );
-            last_err = Some(e);
-        };
-
-        if !quiet {
-            println!(
-                "        Downloading {} [attempt {}/{}]",
-                compressed_file_name.as_ref(),
-                i,
-                max_tries,
-            );
-        }
-
-        let mut dl_resp = match client
-            .get(&dl_uri)
-            .send()
-            .map_err(Error::DownloadRequestError)
-        {
-            Ok(dr) => dr,
-            Err(e) => {
-                handle_retry(e);
-
-                continue;
+                    println!(
+                        "        Downloading {} [attempt {}/{}]",
+                        compressed_file_name.as_ref(),
+                        i,
+                        retry_policy.max_tries,
+                    );
+                }
             }
-        };
-        if !dl_resp.status().is_success() {
-            handle_retry(Error::DownloadRequestStatusError(dl_resp.status()));
 
-            continue;
-        }
-
-        {
-            let mut dled_file = util::create_file(&compressed_file_path)?;
-            dl_resp.copy_to(&mut dled_file).map_err(|re| {
-                Error::CopyIntoFileError(compressed_file_path.clone(), re)
-            })?;
-        }
+            let compressed_size = {
+                let mut dled_file = util::create_file(&compressed_file_path)?;
+                let dl_start = Instant::now();
+                let copied = transport.download(
+                    &dl_uri,
+                    &mut dled_file,
+                    &compressed_file_path,
+                );
+                stats.downloading_secs += dl_start.elapsed().as_secs_f64();
 
-        if !quiet {
-            println!(
-                "        Checking SHA1 hash of {}",
-                compressed_file_name.as_ref(),
-            );
-        }
+                copied?
+            };
+            stats.bytes_downloaded += compressed_size;
 
-        let dled_sha = sha_of_file_by_path(&compressed_file_path, buf)?;
-        if &dled_sha != compressed_sha {
             if !quiet {
-                eprint!("        SHA1 hash mismatch:\n          Local:    ");
-                for b in dled_sha.iter() {
-                    eprint!("{:02x}", b);
-                }
-                eprint!("\n          Manifest: ");
-                for b in compressed_sha.iter() {
-                    eprint!("{:02x}", b);
-                }
-                eprintln!("\n        Re-downloading...");
+                println!(
+                    "        Checking SHA1 hash of {}",
+                    compressed_file_name.as_ref(),
+                );
             }
 
-            last_err = Some(Error::HashMismatch(
-                compressed_file_path.clone(),
-                *compressed_sha,
-            ));
-
-            continue;
-        }
+            let hash_start = Instant::now();
+            let dled_sha = sha_of_file_by_path(&compressed_file_path, buf)?;
+            stats.checking_secs += hash_start.elapsed().as_secs_f64();
+            if &dled_sha != compressed_sha {
+                if !quiet {
+                    eprint!(
+                        "        SHA1 hash mismatch:\n          Local:    "
+                    );
+                    for b in dled_sha.iter() {
+                        eprint!("{:02x}", b);
+                    }
+                    eprint!("\n          Manifest: ");
+                    for b in compressed_sha.iter() {
+                        eprint!("{:02x}", b);
+                    }
+                    eprintln!();
+                }
 
-        if !quiet {
-            println!("        SHA1 hash matches! Extracting...");
-        }
+                return Err(Error::HashMismatch(
+                    compressed_file_path.clone(),
+                    *compressed_sha,
+                ));
+            }
 
-        decompress_file(buf, &compressed_file_path, &decompressed_file_path)?;
+            if !quiet {
+                println!("        SHA1 hash matches! Extracting...");
+            }
 
-        if !quiet {
-            println!("        Checking SHA1 hash of extracted file...");
-        }
+            let extract_start = Instant::now();
+            decompress_file(buf, &compressed_file_path, &temp_file_path)?;
+            stats.extracting_secs += extract_start.elapsed().as_secs_f64();
 
-        let extracted_sha = sha_of_file_by_path(&decompressed_file_path, buf)?;
-        if &extracted_sha != decompressed_sha {
             if !quiet {
-                eprint!("        SHA1 hash mismatch:\n          Local:    ");
-                for b in extracted_sha.iter() {
-                    eprint!("{:02x}", b);
-                }
-                eprint!("\n          Manifest: ");
-                for b in decompressed_sha.iter() {
-                    eprint!("{:02x}", b);
-                }
-                eprintln!("\n        Re-downloading...");
+                println!("        Checking SHA1 hash of extracted file...");
             }
 
-            last_err = Some(Error::HashMismatch(
-                decompressed_file_path.clone(),
-                *decompressed_sha,
-            ));
-
-            continue;
-        }
+            let hash_start = Instant::now();
+            let extracted_sha = sha_of_file_by_path(&temp_file_path, buf)?;
+            stats.checking_secs += hash_start.elapsed().as_secs_f64();
+            if &extracted_sha != decompressed_sha {
+                if !quiet {
+                    eprint!(
+                        "        SHA1 hash mismatch:\n          Local:    "
+                    );
+                    for b in extracted_sha.iter() {
+                        eprint!("{:02x}", b);
+                    }
+                    eprint!("\n          Manifest: ");
+                    for b in decompressed_sha.iter() {
+                        eprint!("{:02x}", b);
+                    }
+                    eprintln!();
+                }
+                // Best-effort, since the next attempt overwrites it anyway.
+                let _ = fs::remove_file(&temp_file_path);
 
-        if !quiet {
-            println!("        SHA1 hash matches!");
-        }
+                return Err(Error::HashMismatch(
+                    decompressed_file_path.clone(),
+                    *decompressed_sha,
+                ));
+            }
 
-        last_err = None;
+            if !quiet {
+                println!("        SHA1 hash matches!");
+            }
+            util::rename_into_place(&temp_file_path, &decompressed_file_path)?;
 
-        break;
-    }
+            Ok(compressed_size)
+        },
+    )?;
 
-    if let Some(e) = last_err {
-        return Err(e);
+    // Only patches go in the cache.
+    if to_cache {
+        summary.stats.bytes_patches += compressed_size;
+    } else {
+        summary.stats.bytes_full_download += compressed_size;
     }
+    summary.downloads.push((
+        compressed_file_name.as_ref().to_owned(),
+        config.cdn_uris[m].clone(),
+    ));
 
     if !quiet {
         println!("        Deleting compressed version...");
@@ -827,9 +1222,11 @@ fn decompress_file<P: AsRef<Path>>(
         })?;
     }
 
-    decoder.finish().map(|_| ()).map_err(|ioe| {
+    let decompressed_file = decoder.finish().map_err(|ioe| {
         Error::DecodeError(decompress_path.as_ref().to_path_buf(), ioe)
-    })
+    })?;
+
+    util::sync_file(&decompressed_file, &decompress_path)
 }
 
 fn ensure_dir<P: AsRef<Path>>(path: P) -> Result<(), Error> {
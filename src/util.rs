@@ -1,5 +1,9 @@
 use crate::error::Error;
-use std::{fs::File, io, path::Path};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
 
 pub fn open_file<P: AsRef<Path>>(path: P) -> Result<File, Error> {
     File::open(&path).map_err(|ioe| match ioe.kind() {
@@ -24,3 +28,207 @@ pub fn create_file<P: AsRef<Path>>(path: P) -> Result<File, Error> {
         }
     })
 }
+
+/// Like `create_file`, but on Unix, only the owner may read or write the
+/// file, even if it already existed.
+pub fn create_private_file<P: AsRef<Path>>(path: P) -> Result<File, Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    let res = {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600).open(&path).and_then(|f| {
+            f.set_permissions(fs::Permissions::from_mode(0o600))
+                .map(|_| f)
+        })
+    };
+    #[cfg(not(unix))]
+    let res = options.open(&path);
+
+    res.map_err(|ioe| match ioe.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied(
+            format!("creating {:?}", path.as_ref()),
+            ioe,
+        ),
+        _ => {
+            Error::UnknownIoError(format!("creating {:?}", path.as_ref()), ioe)
+        }
+    })
+}
+
+/// Where `path` gets written to before being renamed into place. Being in
+/// the same directory as `path` keeps the rename atomic.
+pub fn temp_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut temp_path = path.as_ref().as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    temp_path.into()
+}
+
+/// Makes sure that everything written to `file` has actually hit the disk.
+pub fn sync_file<P: AsRef<Path>>(file: &File, path: P) -> Result<(), Error> {
+    file.sync_all()
+        .map_err(|ioe| Error::FileWriteError(path.as_ref().to_path_buf(), ioe))
+}
+
+/// Renames the already-synced `temp_path` over `path`, and then makes sure
+/// that the rename itself has hit the disk. That way, `path` is always either
+/// entirely the old file or entirely the new one, even if we crash or lose
+/// power along the way.
+pub fn rename_into_place<P: AsRef<Path>, Q: AsRef<Path>>(
+    temp_path: P,
+    path: Q,
+) -> Result<(), Error> {
+    fs::rename(&temp_path, &path).map_err(|_| {
+        Error::FileRenameError(
+            temp_path.as_ref().to_path_buf(),
+            path.as_ref().to_path_buf(),
+        )
+    })?;
+
+    // Renames live in the directory, which has to be synced separately. Not
+    // every filesystem supports that, and the data itself is already safe,
+    // so failure here isn't worth reporting.
+    #[cfg(unix)]
+    {
+        if let Some(dir) = path.as_ref().parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            let _ = File::open(dir).and_then(|d| d.sync_all());
+        }
+    }
+
+    Ok(())
+}
+
+/// Matches `name` against a shell-style glob `pattern`: `*` matches any run
+/// of characters, `?` matches any single character, and `[...]` matches any
+/// single character in the set (which may contain ranges like `0-9`, and is
+/// negated by a leading `!`). Anything else matches itself.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to pick back up if the most recent `*` has to match more.
+    let mut star = None;
+
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match match_class(&pattern[p..], name[n]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                // An unterminated set is just a literal `[`.
+                None if name[n] == '[' => Some(1),
+                None => None,
+            },
+            Some(&c) if c == name[n] => Some(1),
+            _ => None,
+        };
+
+        if let Some(len) = step {
+            p += len;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the `[...]` set at the start of `class`, returning
+/// whether it matched along with the length of the set, or `None` if the set
+/// is never closed.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(class.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let lo = *class.get(i)?;
+        if lo == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        match (class.get(i + 1), class.get(i + 2)) {
+            (Some('-'), Some(&hi)) if hi != ']' => {
+                matched |= lo <= c && c <= hi;
+                i += 3;
+            }
+            _ => {
+                matched |= lo == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_stars_backtrack() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "phase_3.mf"));
+        assert!(glob_match("phase_*.mf", "phase_3.5.mf"));
+        assert!(!glob_match("phase_*.mf", "phase_3.mf.bak"));
+        assert!(glob_match("*ab", "aaab"));
+        assert!(!glob_match("*ab", "aaaba"));
+        assert!(glob_match("*a*b", "xaxxb"));
+        assert!(glob_match("a*b*c", "acbc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        assert!(glob_match("a**", "a"));
+    }
+
+    #[test]
+    fn glob_question_marks() {
+        assert!(glob_match("phase_?.mf", "phase_3.mf"));
+        assert!(!glob_match("phase_?.mf", "phase_35.mf"));
+        assert!(!glob_match("phase_?.mf", "phase_.mf"));
+        // One character, not one byte
+        assert!(glob_match("caf?", "café"));
+    }
+
+    #[test]
+    fn glob_sets() {
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(!glob_match("[a-c]x", "dx"));
+        assert!(glob_match("[!a-z]*", "3x"));
+        assert!(!glob_match("[!a-z]*", "x3"));
+        assert!(glob_match("[^0-9]", "x"));
+        assert!(!glob_match("[^0-9]", "5"));
+        // A leading `]` is part of the set, as is a trailing `-`
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("[a-]", "-"));
+        assert!(!glob_match("[a-]", "b"));
+    }
+
+    #[test]
+    fn glob_unterminated_sets_are_literal() {
+        assert!(glob_match("[abc", "[abc"));
+        assert!(!glob_match("[abc", "a"));
+        assert!(glob_match("file[", "file["));
+        assert!(glob_match("*[!a", "x[!a"));
+    }
+}
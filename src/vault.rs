@@ -0,0 +1,315 @@
+//! An encrypted vault for saved passwords (and TOTP secrets), so that they
+//! don't have to be kept in plain text in the config file. The vault is a JSON
+//! file that holds the key derivation parameters alongside its contents (the
+//! passwords and TOTP secrets, keyed by username), which are encrypted with
+//! XChaCha20-Poly1305 under a key derived from a master password by Argon2id.
+
+use crate::{
+    config::{commit_config, Config},
+    error::Error,
+    util,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// What the vault is called, in the same directory as the config file,
+/// unless the config says otherwise.
+pub const DEFAULT_VAULT_FILE_NAME: &str = "vault.json";
+
+const VAULT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// The vault, as it is on disk.
+#[derive(Deserialize, Serialize)]
+struct VaultFile {
+    version: u32,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// What a vault holds, once it's decrypted.
+#[derive(Deserialize, Serialize, Default)]
+struct Contents {
+    passwords: BTreeMap<String, String>,
+    /// Base32-encoded, as authenticator apps show them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    totp_secrets: BTreeMap<String, String>,
+}
+
+/// An unlocked vault. Changes are only written to disk by `save`.
+pub struct Vault {
+    path: PathBuf,
+    params: Params,
+    salt: [u8; SALT_LEN],
+    cipher: XChaCha20Poly1305,
+    contents: Contents,
+}
+
+impl Vault {
+    /// Creates a new, empty vault at `path` that is unlocked by
+    /// `master_password`, overwriting anything already there.
+    pub fn create<P: Into<PathBuf>>(
+        path: P,
+        master_password: &str,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(Error::RandomnessError)?;
+        let params = Params::default();
+        let cipher = derive_cipher(&path, &params, &salt, master_password)?;
+
+        let vault = Self {
+            path,
+            params,
+            salt,
+            cipher,
+            contents: Contents::default(),
+        };
+        vault.save()?;
+
+        Ok(vault)
+    }
+
+    /// Unlocks the existing vault at `path` with `master_password`.
+    pub fn unlock<P: Into<PathBuf>>(
+        path: P,
+        master_password: &str,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let vault_file: VaultFile =
+            serde_json::from_reader(util::open_file(&path)?)
+                .map_err(Error::DeserializeError)?;
+        if vault_file.version != VAULT_VERSION {
+            return Err(Error::BadVault(path, "unsupported version"));
+        }
+
+        let params = Params::new(
+            vault_file.m_cost,
+            vault_file.t_cost,
+            vault_file.p_cost,
+            Some(KEY_LEN),
+        )
+        .map_err(|_| {
+            Error::BadVault(path.clone(), "bad key derivation parameters")
+        })?;
+        let mut salt = [0u8; SALT_LEN];
+        decode_hex(&vault_file.salt, &mut salt)
+            .ok_or_else(|| Error::BadVault(path.clone(), "bad salt"))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        decode_hex(&vault_file.nonce, &mut nonce)
+            .ok_or_else(|| Error::BadVault(path.clone(), "bad nonce"))?;
+        let mut ciphertext = vec![0u8; vault_file.ciphertext.len() / 2];
+        decode_hex(&vault_file.ciphertext, &mut ciphertext)
+            .ok_or_else(|| Error::BadVault(path.clone(), "bad ciphertext"))?;
+
+        let cipher = derive_cipher(&path, &params, &salt, master_password)?;
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: associated_data(vault_file.version, &params, &salt)
+                        .as_bytes(),
+                },
+            )
+            .map_err(|_| Error::VaultDecryptError(path.clone()))?;
+        let contents = serde_json::from_slice(&plaintext)
+            .map_err(Error::DeserializeError)?;
+
+        Ok(Self {
+            path,
+            params,
+            salt,
+            cipher,
+            contents,
+        })
+    }
+
+    /// Encrypts the vault's contents, and writes them to disk.
+    pub fn save(&self) -> Result<(), Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(Error::RandomnessError)?;
+        let plaintext = serde_json::to_vec(&self.contents)
+            .map_err(Error::SerializeError)?;
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: associated_data(
+                        VAULT_VERSION,
+                        &self.params,
+                        &self.salt,
+                    )
+                    .as_bytes(),
+                },
+            )
+            .map_err(|_| Error::VaultEncryptError(self.path.clone()))?;
+
+        let vault_file = VaultFile {
+            version: VAULT_VERSION,
+            m_cost: self.params.m_cost(),
+            t_cost: self.params.t_cost(),
+            p_cost: self.params.p_cost(),
+            salt: encode_hex(&self.salt),
+            nonce: encode_hex(&nonce),
+            ciphertext: encode_hex(&ciphertext),
+        };
+
+        let temp_path = util::temp_path(&self.path);
+        // Nobody else gets to try to brute-force the master password.
+        let mut temp_file = util::create_private_file(&temp_path)?;
+        serde_json::to_writer_pretty(&mut temp_file, &vault_file)
+            .map_err(Error::SerializeError)?;
+        temp_file
+            .flush()
+            .map_err(|ioe| Error::FileWriteError(temp_path.clone(), ioe))?;
+        util::sync_file(&temp_file, &temp_path)?;
+        drop(temp_file);
+
+        util::rename_into_place(&temp_path, &self.path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, username: &str) -> Option<&str> {
+        self.contents.passwords.get(username).map(String::as_str)
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.contents.passwords.contains_key(username)
+    }
+
+    /// Same return type as `BTreeMap::insert`.
+    pub fn insert(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Option<String> {
+        self.contents.passwords.insert(username, password)
+    }
+
+    /// Same return type as `BTreeMap::remove`.
+    pub fn remove(&mut self, username: &str) -> Option<String> {
+        self.contents.passwords.remove(username)
+    }
+
+    /// The base32-encoded TOTP secret for `username`, if any.
+    pub fn totp_secret(&self, username: &str) -> Option<&str> {
+        self.contents.totp_secrets.get(username).map(String::as_str)
+    }
+
+    /// Same return type as `BTreeMap::insert`.
+    pub fn insert_totp_secret(
+        &mut self,
+        username: String,
+        secret: String,
+    ) -> Option<String> {
+        self.contents.totp_secrets.insert(username, secret)
+    }
+
+    /// Same return type as `BTreeMap::remove`.
+    pub fn remove_totp_secret(&mut self, username: &str) -> Option<String> {
+        self.contents.totp_secrets.remove(username)
+    }
+}
+
+/// Moves any passwords that are still in plain text in `config` into `vault`,
+/// and then removes them from the config file. The vault is saved first, so
+/// that a failure along the way can't lose any passwords. Returns how many
+/// passwords were moved.
+pub fn migrate<P: AsRef<Path>>(
+    config: &mut Config,
+    config_path: P,
+    vault: &mut Vault,
+) -> Result<usize, Error> {
+    let plaintext: Vec<(String, String)> = config
+        .accounts
+        .iter()
+        .filter_map(|(username, val)| {
+            val.as_str().map(|p| (username.clone(), p.to_owned()))
+        })
+        .collect();
+    if plaintext.is_empty() {
+        return Ok(0);
+    }
+
+    for (username, password) in plaintext.iter().cloned() {
+        vault.insert(username, password);
+    }
+    vault.save()?;
+
+    for (username, _) in plaintext.iter() {
+        config
+            .accounts
+            .insert(username.clone(), serde_json::Value::Null);
+    }
+    commit_config(config, config_path)?;
+
+    Ok(plaintext.len())
+}
+
+fn derive_cipher(
+    path: &Path,
+    params: &Params,
+    salt: &[u8],
+    master_password: &str,
+) -> Result<XChaCha20Poly1305, Error> {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(master_password.as_bytes(), salt, &mut key)
+        .map_err(|_| {
+            Error::BadVault(path.to_owned(), "bad key derivation parameters")
+        })?;
+
+    XChaCha20Poly1305::new_from_slice(&key)
+        .map_err(|_| Error::BadVault(path.to_owned(), "bad key length"))
+}
+
+/// Ties the ciphertext to the key derivation parameters that it was written
+/// with, so that they can't be tampered with unnoticed.
+fn associated_data(version: u32, params: &Params, salt: &[u8]) -> String {
+    format!(
+        "v{}:m{}:t{}:p{}:{}",
+        version,
+        params.m_cost(),
+        params.t_cost(),
+        params.p_cost(),
+        encode_hex(salt),
+    )
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes `s` into `out`, which must be exactly the right length.
+fn decode_hex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 {
+        return None;
+    }
+
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+
+    Some(())
+}
@@ -0,0 +1,326 @@
+use crate::{
+    command,
+    config::Config,
+    error::Error,
+    play::{self, Secrets},
+    retry::RetryPolicy,
+    update::{self, FileFilter},
+};
+#[cfg(target_os = "linux")]
+use clap::crate_name;
+use reqwest::blocking as rb;
+#[cfg(target_os = "linux")]
+use std::{env, fs, num::NonZeroUsize};
+use std::{
+    path::Path,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+/// How often to check on running game instances while waiting.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Checks for updates, and applies them unless `dry`. If any game instances
+/// are running, applying the updates waits until they have all exited. If
+/// `interval` is supplied, this repeats every `interval` (and never returns
+/// unless something goes wrong); otherwise, the check only happens once.
+///
+/// `maybe_usernames` are logged in after the first check, so that they are
+/// playing on an up-to-date installation.
+#[allow(clippy::too_many_arguments)]
+pub fn watch<'a, P: AsRef<Path>, U: Iterator<Item = &'a str>>(
+    config: &mut Config,
+    config_path: P,
+    client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    filter: &FileFilter,
+    keep_going: bool,
+    quiet: bool,
+    dry: bool,
+    interval: Option<Duration>,
+    mut maybe_usernames: Option<U>,
+    secrets: &mut Secrets,
+) -> Result<(), Error> {
+    let mut children = Vec::new();
+
+    loop {
+        if let Err(e) = check_and_update(
+            config,
+            client,
+            retry_policy,
+            filter,
+            keep_going,
+            quiet,
+            dry,
+            &mut children,
+        ) {
+            if interval.is_none() {
+                return Err(e);
+            }
+
+            // Just because the network is down right now doesn't mean that
+            // it will be next time.
+            eprintln!("{}", e);
+        }
+
+        if let Some(usernames) = maybe_usernames.take() {
+            play::login(
+                config,
+                &config_path,
+                client,
+                retry_policy,
+                quiet,
+                usernames,
+                secrets,
+                &mut children,
+            )?;
+        }
+
+        let interval = if let Some(i) = interval {
+            i
+        } else {
+            return Ok(());
+        };
+
+        if !quiet {
+            println!(
+                "Checking again in {} minute(s)...\n",
+                interval.as_secs() / 60,
+            );
+        }
+
+        let start = Instant::now();
+        while let Some(remaining) = interval.checked_sub(start.elapsed()) {
+            thread::sleep(remaining.min(POLL_INTERVAL));
+            command::check_children(quiet, &mut children)?;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn check_and_update(
+    config: &Config,
+    client: &rb::Client,
+    retry_policy: &RetryPolicy,
+    filter: &FileFilter,
+    keep_going: bool,
+    quiet: bool,
+    dry: bool,
+    children: &mut Vec<(String, process::Child, Instant)>,
+) -> Result<(), Error> {
+    if !quiet {
+        println!("Checking for updates...");
+    }
+
+    let summary = update::update(
+        config,
+        client,
+        true,
+        retry_policy,
+        true,
+        filter,
+        keep_going,
+    )?;
+    if !summary.held.is_empty() && (dry || !quiet) {
+        println!("{} file(s) are held back:", summary.held.len());
+        for file_name in summary.held.iter() {
+            println!("    {} (held)", file_name);
+        }
+    }
+
+    let pending = summary.pending;
+    if pending.is_empty() {
+        if !quiet {
+            println!("Everything is up to date!");
+        }
+
+        return Ok(());
+    }
+
+    // Reporting the available updates is the whole point of a dry run, so
+    // this happens even if `quiet`.
+    if dry || !quiet {
+        println!("{} file(s) can be updated:", pending.len());
+        for (file_name, pending_update) in pending.iter() {
+            println!("    {} ({})", file_name, pending_update);
+        }
+    }
+    if dry {
+        return Ok(());
+    }
+
+    let mut waiting = false;
+    loop {
+        command::check_children(quiet, children)?;
+        if children.is_empty() && !game_running(config) {
+            break;
+        }
+
+        if !waiting && !quiet {
+            println!(
+                "The game is still running, waiting for it to exit before \
+                 updating..."
+            );
+        }
+        waiting = true;
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    update::update(
+        config,
+        client,
+        quiet,
+        retry_policy,
+        false,
+        filter,
+        keep_going,
+    )
+    .map(|_| ())
+}
+
+/// Whether or not any game instance is running out of this installation,
+/// including ones that weren't launched by us.
+#[cfg(target_os = "linux")]
+fn game_running(config: &Config) -> bool {
+    let engine_path = config.install_dir.join("TTREngine");
+    let engine_path = fs::canonicalize(&engine_path).unwrap_or(engine_path);
+
+    let procs = if let Ok(rd) = fs::read_dir("/proc") {
+        rd
+    } else {
+        return false;
+    };
+
+    procs.filter_map(Result::ok).any(|entry| {
+        entry
+            .file_name()
+            .to_str()
+            .map(|pid| pid.bytes().all(|b| b.is_ascii_digit()))
+            .unwrap_or(false)
+            && fs::read_link(entry.path().join("exe"))
+                .map(|exe| exe == engine_path)
+                .unwrap_or(false)
+    })
+}
+
+/// Only game instances that we launched ourselves can be detected on this
+/// platform.
+#[cfg(not(target_os = "linux"))]
+fn game_running(_config: &Config) -> bool {
+    false
+}
+
+/// Writes a systemd user service that does a single `--watch-once` check,
+/// along with a timer that runs said service every `interval_minutes`.
+#[cfg(target_os = "linux")]
+pub fn write_systemd_units<P: AsRef<Path>, Q: AsRef<Path>>(
+    unit_dir: P,
+    config: &Config,
+    config_path: Q,
+    max_tries: NonZeroUsize,
+    interval_minutes: u64,
+    quiet: bool,
+) -> Result<(), Error> {
+    const SERVICE_NAME: &str = concat!(crate_name!(), "-update.service");
+    const TIMER_NAME: &str = concat!(crate_name!(), "-update.timer");
+
+    let exe = env::current_exe().map_err(|ioe| {
+        Error::UnknownIoError(
+            "finding the path of the current executable".to_owned(),
+            ioe,
+        )
+    })?;
+
+    let mut exec_start = systemd_quote(&exe);
+    if config_path.as_ref().as_os_str().is_empty() {
+        exec_start += " --no-config --install-dir ";
+        exec_start += &systemd_quote(&config.install_dir);
+        exec_start += " --cache-dir ";
+        exec_start += &systemd_quote(&config.cache_dir);
+    } else {
+        let config_path = fs::canonicalize(&config_path).map_err(|ioe| {
+            Error::UnknownIoError(
+                format!("canonicalizing {:?}", config_path.as_ref()),
+                ioe,
+            )
+        })?;
+
+        exec_start += " --config ";
+        exec_start += &systemd_quote(config_path);
+    }
+    exec_start += &format!(" --tries {} --quiet --watch-once", max_tries);
+
+    let service = format!(
+        "[Unit]\n\
+         Description=Check for and apply Toontown Rewritten updates\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={}\n",
+        exec_start,
+    );
+    let timer = format!(
+        "[Unit]\n\
+         Description=Periodically check for Toontown Rewritten updates\n\
+         \n\
+         [Timer]\n\
+         OnStartupSec=1min\n\
+         OnUnitActiveSec={}min\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        interval_minutes,
+    );
+
+    fs::create_dir_all(&unit_dir).map_err(|ioe| {
+        Error::MkdirError(unit_dir.as_ref().to_path_buf(), ioe)
+    })?;
+    for (name, contents) in
+        [(SERVICE_NAME, service), (TIMER_NAME, timer)].iter()
+    {
+        let path = unit_dir.as_ref().join(name);
+        fs::write(&path, contents)
+            .map_err(|ioe| Error::FileWriteError(path.clone(), ioe))?;
+
+        if !quiet {
+            println!("Wrote {}", path.display());
+        }
+    }
+
+    if !quiet {
+        println!(
+            "\nTo start checking for updates in the background, run:\n\n    \
+             systemctl --user daemon-reload\n    \
+             systemctl --user enable --now {}",
+            TIMER_NAME,
+        );
+    }
+
+    Ok(())
+}
+
+/// Quotes a path for use as a single argument in a systemd `ExecStart=`.
+#[cfg(target_os = "linux")]
+fn systemd_quote<P: AsRef<Path>>(path: P) -> String {
+    let raw = path.as_ref().to_string_lossy();
+    let mut quoted = String::with_capacity(raw.len() + 2);
+
+    quoted.push('"');
+    for c in raw.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            // Specifiers such as `%h` and variables such as `$HOME` are
+            // expanded even within quotes.
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
@@ -0,0 +1,257 @@
+//! End-to-end tests of updating and logging in, against the mock TTR server.
+
+#![cfg(feature = "mock-server")]
+
+use bzip2::{write::BzEncoder, Compression};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use shticker_book_unwritten::{
+    config::Config,
+    error::Error,
+    login::{self, LoginHandler, LoginOutcome},
+    mock::{MockAccount, MockServer, LOGIN_API_PATH, MOCK_GAME_SERVER},
+    net,
+    retry::RetryPolicy,
+    update::{self, FileFilter},
+};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::Write,
+    net::{Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The files in the mock manifest, along with their contents. On Unix, the
+/// game's executable has to be among them for an update to succeed.
+const GAME_FILES: &[(&str, &[u8])] = &[
+    ("phase_3.mf", b"Not actually a multifile, but close enough"),
+    #[cfg(target_os = "linux")]
+    ("TTREngine", b"#!/bin/sh\n"),
+    #[cfg(target_os = "macos")]
+    ("Toontown Rewritten", b"#!/bin/sh\n"),
+];
+
+/// Answers two-factor authentication with a fixed token, and counts how many
+/// times that the login API has queued us.
+struct Handler {
+    token: &'static str,
+    times_queued: usize,
+}
+
+impl LoginHandler for Handler {
+    fn two_factor_token(
+        &mut self,
+        _banner: &str,
+    ) -> Result<Option<String>, Error> {
+        Ok(Some(self.token.to_owned()))
+    }
+
+    fn queued(
+        &mut self,
+        _eta: f64,
+        _position: Option<u64>,
+    ) -> Result<(), Error> {
+        self.times_queued += 1;
+
+        Ok(())
+    }
+}
+
+/// A fresh, empty directory for the test called `name`.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "shticker_book_unwritten-{}-{}",
+        name,
+        process::id(),
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+fn sha1_hex(bytes: &[u8]) -> String {
+    Sha1::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Lays out a manifest of `GAME_FILES`, and the CDN that they are on, under
+/// `root`.
+fn serve_game_files(root: &Path) {
+    fs::create_dir_all(root.join("cdn")).unwrap();
+
+    let mut manifest = serde_json::Map::new();
+    for (file_name, contents) in GAME_FILES.iter() {
+        let mut encoder = BzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let dl = format!("{}.bz2", file_name);
+        fs::write(root.join("cdn").join(&dl), &compressed).unwrap();
+
+        manifest.insert(
+            (*file_name).to_owned(),
+            json!({
+                "dl": dl,
+                "compHash": sha1_hex(&compressed),
+                "hash": sha1_hex(contents),
+                "only": [update::OS_AND_ARCH],
+                "patches": {},
+            }),
+        );
+    }
+    fs::write(
+        root.join("manifest.txt"),
+        serde_json::Value::Object(manifest).to_string(),
+    )
+    .unwrap();
+}
+
+fn start_server(root: &Path) -> MockServer {
+    let mut accounts = BTreeMap::new();
+    accounts.insert(
+        "toon".to_owned(),
+        MockAccount {
+            password: "hunter2".to_owned(),
+            toonguard: Some("123456".to_owned()),
+            queue: 2,
+            ..MockAccount::default()
+        },
+    );
+
+    MockServer::start(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        root,
+        accounts,
+    )
+    .unwrap()
+}
+
+fn mock_config(server: &MockServer, dir: &Path) -> Config {
+    let mut config = Config::new(dir.join("install"), dir.join("cache"));
+    config.manifest_uris = vec![server.uri("manifest.txt")];
+    config.cdn_uris = vec![server.uri("cdn/")];
+    config.login_api_uri = server.uri(LOGIN_API_PATH);
+
+    config
+}
+
+#[test]
+fn update_from_mock_server() {
+    let dir = scratch_dir("update");
+    let root = dir.join("server");
+    serve_game_files(&root);
+    let server = start_server(&root);
+    let config = mock_config(&server, &dir);
+    let client = net::build_client(&config.network).unwrap();
+    let retry_policy = RetryPolicy::new(NonZeroUsize::new(2).unwrap());
+    let filter = FileFilter::from_config(&config);
+
+    let summary = update::update(
+        &config,
+        &client,
+        true,
+        &retry_policy,
+        false,
+        &filter,
+        false,
+    )
+    .unwrap();
+    assert_eq!(summary.downloaded.len(), GAME_FILES.len());
+    for (file_name, contents) in GAME_FILES.iter() {
+        assert_eq!(
+            fs::read(config.install_dir.join(file_name)).unwrap(),
+            *contents,
+        );
+    }
+
+    // Now that it's up to date, there's nothing left to do.
+    let summary = update::update(
+        &config,
+        &client,
+        true,
+        &retry_policy,
+        false,
+        &filter,
+        false,
+    )
+    .unwrap();
+    assert!(summary.downloaded.is_empty());
+    assert_eq!(summary.stats.files_up_to_date, GAME_FILES.len());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn login_to_mock_server() {
+    let dir = scratch_dir("login");
+    let root = dir.join("server");
+    fs::create_dir_all(&root).unwrap();
+    let server = start_server(&root);
+    let config = mock_config(&server, &dir);
+    let client = net::build_client(&config.network).unwrap();
+    let retry_policy = RetryPolicy::new(NonZeroUsize::new(2).unwrap());
+
+    let mut handler = Handler {
+        token: "123456",
+        times_queued: 0,
+    };
+    match login::negotiate(
+        &client,
+        &retry_policy,
+        &config.login_api_uri,
+        "toon",
+        "hunter2",
+        &mut handler,
+    )
+    .unwrap()
+    {
+        LoginOutcome::Success(session) => {
+            assert_eq!(session.play_cookie, "mock-cookie-toon");
+            assert_eq!(session.game_server, MOCK_GAME_SERVER);
+        }
+        outcome => panic!("Expected to log in, got {:?}", outcome),
+    }
+    assert_eq!(handler.times_queued, 2);
+
+    let mut handler = Handler {
+        token: "654321",
+        times_queued: 0,
+    };
+    match login::negotiate(
+        &client,
+        &retry_policy,
+        &config.login_api_uri,
+        "toon",
+        "hunter2",
+        &mut handler,
+    )
+    .unwrap()
+    {
+        LoginOutcome::Failed(_) => (),
+        outcome => panic!("Expected a bad token to fail, got {:?}", outcome),
+    }
+
+    match login::negotiate(
+        &client,
+        &retry_policy,
+        &config.login_api_uri,
+        "toon",
+        "hunter3",
+        &mut handler,
+    )
+    .unwrap()
+    {
+        LoginOutcome::Failed(_) => (),
+        outcome => {
+            panic!("Expected a bad password to fail, got {:?}", outcome)
+        }
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}